@@ -0,0 +1,75 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use indumi::currency::CurrencyConverter;
+use indumi::parser::tokenize;
+use indumi::{Calculator, Parser};
+
+// Representative lines: plain arithmetic, deep nesting, a currency conversion and functions
+fn inputs() -> Vec<(&'static str, String)> {
+    let depth = 50;
+    vec![
+        ("arithmetic", "2 + 3 * 4 - 10 / 5 ^ 2".to_string()),
+        ("nested", format!("{}1 + 1{}", "(".repeat(depth), " * 2)".repeat(depth))),
+        ("currency", "100 USD + 2.5k USD to INR".to_string()),
+        ("functions", "sqrt(16) + nCr(10, 3) * 15% of 200".to_string()),
+    ]
+}
+
+// Offline rates, so results don't depend on the network
+fn calculator() -> Calculator {
+    Calculator::with_converter(CurrencyConverter::offline())
+}
+
+fn bench_tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+    for (name, input) in inputs() {
+        group.bench_function(name, |b| b.iter(|| tokenize(black_box(&input))));
+    }
+    group.finish();
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let parser = Parser::new();
+    let mut group = c.benchmark_group("parse");
+    for (name, input) in inputs() {
+        group.bench_function(name, |b| b.iter(|| parser.parse(black_box(&input)).unwrap()));
+    }
+    group.finish();
+}
+
+fn bench_evaluate(c: &mut Criterion) {
+    let parser = Parser::new();
+    let mut calc = calculator();
+    let mut group = c.benchmark_group("evaluate");
+    for (name, input) in inputs() {
+        let expr = parser.parse(&input).unwrap();
+        group.bench_function(name, |b| b.iter(|| calc.evaluate(black_box(&expr)).unwrap()));
+    }
+    group.finish();
+}
+
+// What every render does: the whole sheet, top to bottom
+fn bench_sheet(c: &mut Criterion) {
+    let mut calc = calculator();
+    let lines: Vec<String> = [
+        "salary = 85000",
+        "rent = 1500",
+        "food = 400",
+        "savings = salary - rent * 12 - food * 12",
+        "savings / 12",
+        "",
+        "100 USD to INR",
+        "50 EUR to USD",
+        "15% of 2000",
+        "total: rent + food",
+    ]
+    .iter()
+    .cycle()
+    .take(100)
+    .map(|line| line.to_string())
+    .collect();
+
+    c.bench_function("evaluate_all/100_lines", |b| b.iter(|| calc.evaluate_all(black_box(&lines))));
+}
+
+criterion_group!(benches, bench_tokenize, bench_parse, bench_evaluate, bench_sheet);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+use base64::Engine;
+use std::io::{self, Write};
+
+/// Copy `text` to the system clipboard using the OSC 52 escape sequence, which most
+/// terminal emulators support, including over SSH
+pub fn copy(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(osc52_sequence(text).as_bytes())?;
+    stdout.flush()
+}
+
+fn osc52_sequence(text: &str) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    format!("\x1b]52;c;{}\x07", encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(osc52_sequence("₹ 8,350"), "\x1b]52;c;4oK5IDgsMzUw\x07");
+    }
+}
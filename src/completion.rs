@@ -0,0 +1,70 @@
+use crate::calc::{Calculator, CONSTANTS, FUNCTION_NAMES};
+use crate::parser::CURRENCY_CODES;
+
+/// Candidates for completing the identifier `prefix`: currency codes, built-in and
+/// registered function names and defined variables, matched case-insensitively and sorted.
+pub fn completions(prefix: &str, calc: &Calculator) -> Vec<String> {
+    if prefix.is_empty() {
+        return Vec::new();
+    }
+
+    let lowered = prefix.to_lowercase();
+    let mut candidates: Vec<String> = CURRENCY_CODES
+        .iter()
+        .chain(FUNCTION_NAMES.iter())
+        .chain(CONSTANTS.iter().map(|(name, _)| name))
+        .map(|name| name.to_string())
+        .chain(calc.custom_function_names())
+        .chain(calc.variable_names())
+        .filter(|name| name.to_lowercase().starts_with(&lowered))
+        // Nothing to complete when the word is already typed out in full
+        .filter(|name| name.to_lowercase() != lowered)
+        .collect();
+
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_test_calculator() -> Calculator {
+        Calculator::new().await.expect("Failed to create calculator")
+    }
+
+    #[tokio::test]
+    async fn test_completes_currency_codes() {
+        let calc = create_test_calculator().await;
+        assert_eq!(completions("us", &calc), vec!["USD"]);
+        assert_eq!(completions("E", &calc), vec!["EUR"]);
+    }
+
+    #[tokio::test]
+    async fn test_completes_function_names() {
+        let calc = create_test_calculator().await;
+        assert_eq!(completions("sq", &calc), vec!["sqrt"]);
+        assert_eq!(completions("l", &calc), vec!["ln", "log"]);
+        assert_eq!(completions("conv", &calc), vec!["convert"]);
+    }
+
+    #[tokio::test]
+    async fn test_completes_variables() {
+        let mut calc = create_test_calculator().await;
+        calc.evaluate_line("salary = 5000");
+        calc.evaluate_line("savings = 1500");
+        assert_eq!(completions("sa", &calc), vec!["salary", "savings"]);
+        // Variables mix with the other sources
+        calc.evaluate_line("insurance = 200");
+        assert_eq!(completions("in", &calc), vec!["INR", "insurance"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_completions() {
+        let calc = create_test_calculator().await;
+        assert!(completions("", &calc).is_empty());
+        assert!(completions("zzz", &calc).is_empty());
+        assert!(completions("usd", &calc).is_empty());
+    }
+}
@@ -1,6 +1,59 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
 use reqwest;
 use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+const RATES_URL: &str = "https://api.exchangerate-api.com/v4/latest/USD";
+
+/// A currency code such as `USD`: three ASCII letters, always upper case
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Currency(String);
+
+impl Currency {
+    /// Parse a code or symbol, in any case and with an optional trailing period:
+    /// `usd`, `USD.` and `$` all give `USD`.
+    pub fn new(code: &str) -> Result<Self, String> {
+        let trimmed = code.trim();
+        let upper = trimmed.strip_suffix('.').unwrap_or(trimmed).to_uppercase();
+        let upper = match upper.as_str() {
+            "$" => "USD".to_string(),
+            "€" => "EUR".to_string(),
+            "₹" => "INR".to_string(),
+            _ => upper,
+        };
+
+        if upper.len() == 3 && upper.chars().all(|c| c.is_ascii_alphabetic()) {
+            Ok(Self(upper))
+        } else {
+            Err(format!("Invalid currency: {}", code))
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<str> for Currency {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Currency {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
 
 #[derive(Deserialize)]
 struct ExchangeRateResponse {
@@ -10,50 +63,344 @@ struct ExchangeRateResponse {
 #[derive(Debug)]
 pub struct CurrencyConverter {
     rates: HashMap<String, f64>,
+    // Bumped on every successful rate update so callers can spot stale results
+    version: u64,
+    // Spread deducted from every conversion, in percent of the converted amount
+    conversion_fee_pct: f64,
+    // Rates pinned by the user, (from, to) → units of `to` per `from`. Checked before `rates`.
+    overrides: HashMap<(Currency, Currency), f64>,
 }
 
 impl CurrencyConverter {
+    /// Converter with live rates, falling back to the built-in ones if the fetch fails.
+    /// With `INDUMI_OFFLINE=1` set, the network is never touched.
     pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let mut rates = HashMap::new();
+        if offline_requested() {
+            return Ok(Self::offline());
+        }
+
+        let mut converter = Self::offline();
 
         // Try to fetch from API
-        match Self::fetch_rates().await {
-            Ok(api_rates) => {
-                rates = api_rates;
-            }
-            Err(e) => {
-                eprintln!("Failed to fetch currency rates: {}. Using fallback rates.", e);
-                // Fallback rates
-                rates.insert("USD".to_string(), 1.0);
-                rates.insert("EUR".to_string(), 0.92);
-                rates.insert("INR".to_string(), 83.50);
-            }
+        if let Err(e) = converter.refresh().await {
+            eprintln!("Failed to fetch currency rates: {}. Using fallback rates.", e);
+        }
+
+        Ok(converter)
+    }
+
+    /// Converter using the built-in fallback rates, without touching the network
+    pub fn offline() -> Self {
+        let mut rates = HashMap::new();
+        rates.insert("USD".to_string(), 1.0);
+        rates.insert("EUR".to_string(), 0.92);
+        rates.insert("INR".to_string(), 83.50);
+
+        Self {
+            rates,
+            version: 0,
+            conversion_fee_pct: 0.0,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Converter with the rates in a JSON file shaped like the API's response,
+    /// `{"rates": {"USD": 1, "EUR": 0.92, ...}}`, for runs that don't depend on the network
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let rates = Self::parse_rates(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut converter = Self::offline();
+        converter.update_rates(rates);
+        Ok(converter)
+    }
+
+    fn parse_rates(json: &str) -> Result<HashMap<String, f64>, String> {
+        let data: ExchangeRateResponse = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        if data.rates.is_empty() {
+            return Err("No rates".to_string());
+        }
+        if let Some((code, rate)) = data.rates.iter().find(|(_, rate)| !(rate.is_finite() && **rate > 0.0)) {
+            return Err(format!("Invalid rate for {}: {}", code, rate));
         }
+        Ok(data.rates)
+    }
+
+    /// Re-fetch rates from the API. On failure the current rates are kept.
+    pub async fn refresh(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let rates = Self::fetch_rates(RATES_URL).await?;
+        self.update_rates(rates);
+        Ok(())
+    }
+
+    /// Replace all rates (relative to USD) and bump the version
+    pub fn update_rates(&mut self, rates: HashMap<String, f64>) {
+        self.rates = rates;
+        self.version += 1;
+    }
+
+    /// Codes of every currency the current rates cover, sorted
+    pub fn supported_currencies(&self) -> Vec<String> {
+        let mut codes: Vec<String> = self.rates.keys().cloned().collect();
+        codes.sort();
+        codes
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Deduct `pct` percent from every converted amount, like a bank's spread
+    pub fn set_fee(&mut self, pct: f64) {
+        self.conversion_fee_pct = pct;
+    }
+
+    pub fn fee(&self) -> f64 {
+        self.conversion_fee_pct
+    }
 
-        Ok(Self { rates })
+    /// Re-fetch rates every `interval` in a background task and swap them into `converter`,
+    /// bumping its version so anything holding the converter can spot the change. A failed
+    /// fetch keeps the current rates until the next attempt. Abort the handle to stop it.
+    pub fn spawn_updater(converter: Arc<RwLock<CurrencyConverter>>, interval: Duration) -> JoinHandle<()> {
+        Self::spawn_updater_from(converter, RATES_URL.to_string(), interval)
     }
 
-    async fn fetch_rates() -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
-        let url = "https://api.exchangerate-api.com/v4/latest/USD";
+    fn spawn_updater_from(converter: Arc<RwLock<CurrencyConverter>>, url: String, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // Fetch before taking the lock so conversions aren't blocked on the network
+                if let Ok(rates) = Self::fetch_rates(&url).await {
+                    converter.write().unwrap_or_else(PoisonError::into_inner).update_rates(rates);
+                }
+            }
+        })
+    }
+
+    async fn fetch_rates(url: &str) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
         let response = reqwest::get(url).await?;
         let data: ExchangeRateResponse = response.json().await?;
         Ok(data.rates)
     }
 
-    pub fn convert(&self, amount: f64, from: &str, to: &str) -> Result<f64, String> {
+    /// Pin the rate between two currencies until it's cleared; fetched rates don't replace it.
+    /// The reverse direction uses the reciprocal.
+    pub fn set_rate(&mut self, from: &Currency, to: &Currency, rate: f64) {
+        self.overrides.remove(&(to.clone(), from.clone()));
+        self.overrides.insert((from.clone(), to.clone()), rate);
+    }
+
+    /// Remove a pinned rate in either direction. Returns whether there was one.
+    pub fn clear_rate(&mut self, from: &Currency, to: &Currency) -> bool {
+        let forward = self.overrides.remove(&(from.clone(), to.clone()));
+        let backward = self.overrides.remove(&(to.clone(), from.clone()));
+        forward.is_some() || backward.is_some()
+    }
+
+    /// Units of `to` per unit of `from`, before any fee
+    pub fn rate(&self, from: &Currency, to: &Currency) -> Result<f64, String> {
+        if let Some(rate) = self.overrides.get(&(from.clone(), to.clone())) {
+            return Ok(*rate);
+        }
+        if let Some(rate) = self.overrides.get(&(to.clone(), from.clone())) {
+            return Ok(1.0 / rate);
+        }
+
         let from_rate = self
             .rates
-            .get(from)
+            .get(from.code())
             .ok_or_else(|| format!("Unknown currency: {}", from))?;
         let to_rate = self
             .rates
-            .get(to)
+            .get(to.code())
             .ok_or_else(|| format!("Unknown currency: {}", to))?;
 
-        // Convert to USD first, then to target currency
-        let usd_amount = amount / from_rate;
-        let result = usd_amount * to_rate;
+        // Rates are relative to USD, so go through USD
+        Ok(to_rate / from_rate)
+    }
+
+    /// Converted amount after the fee is deducted
+    pub fn convert(&self, amount: f64, from: &Currency, to: &Currency) -> Result<f64, String> {
+        self.convert_with_fee(amount, from, to).map(|(net, _)| net)
+    }
+
+    /// Converted amount after the fee, and the fee itself, both in the target currency
+    pub fn convert_with_fee(&self, amount: f64, from: &Currency, to: &Currency) -> Result<(f64, f64), String> {
+        let result = amount * self.rate(from, to)?;
+        let fee = result * self.conversion_fee_pct / 100.0;
+        Ok((result - fee, fee))
+    }
+}
+
+/// Whether the `INDUMI_OFFLINE` environment variable asks to skip fetching rates
+pub fn offline_requested() -> bool {
+    is_truthy(std::env::var("INDUMI_OFFLINE").ok().as_deref())
+}
+
+fn is_truthy(value: Option<&str>) -> bool {
+    matches!(
+        value.map(|v| v.trim().to_lowercase()).as_deref(),
+        Some("1") | Some("true") | Some("yes")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(code: &str) -> Currency {
+        Currency::new(code).unwrap()
+    }
+
+    #[test]
+    fn test_rates_from_file() {
+        let path = std::env::temp_dir().join(format!("indumi_rates_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"base": "USD", "rates": {"USD": 1, "EUR": 0.5, "GBP": 0.8}}"#).unwrap();
+        let converter = CurrencyConverter::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(converter.supported_currencies(), vec!["EUR", "GBP", "USD"]);
+        assert_eq!(converter.convert(10.0, &c("USD"), &c("EUR")).unwrap(), 5.0);
+        assert_eq!(converter.convert(10.0, &c("EUR"), &c("GBP")).unwrap(), 16.0);
+        assert!(converter.convert(1.0, &c("USD"), &c("INR")).is_err());
+
+        assert!(CurrencyConverter::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_parse_rates_rejects_bad_files() {
+        assert!(CurrencyConverter::parse_rates("not json").is_err());
+        assert_eq!(CurrencyConverter::parse_rates(r#"{"rates": {}}"#), Err("No rates".to_string()));
+        assert_eq!(CurrencyConverter::parse_rates(r#"{"rates": {"EUR": 0}}"#), Err("Invalid rate for EUR: 0".to_string()));
+    }
+
+    #[test]
+    fn test_offline_uses_fallback_rates() {
+        let converter = CurrencyConverter::offline();
+        assert_eq!(converter.version(), 0);
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("INR")).unwrap(), 83.5);
+    }
+
+    #[test]
+    fn test_version_increases_on_update() {
+        let mut converter = CurrencyConverter::offline();
+        let mut rates = HashMap::new();
+        rates.insert("USD".to_string(), 1.0);
+        rates.insert("INR".to_string(), 85.0);
+
+        converter.update_rates(rates.clone());
+        assert_eq!(converter.version(), 1);
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("INR")).unwrap(), 85.0);
+
+        converter.update_rates(rates);
+        assert_eq!(converter.version(), 2);
+    }
+
+    #[test]
+    fn test_supported_currencies() {
+        let mut converter = CurrencyConverter::offline();
+        assert_eq!(converter.supported_currencies(), vec!["EUR", "INR", "USD"]);
+
+        let rates = HashMap::from([("USD".to_string(), 1.0), ("GBP".to_string(), 0.79)]);
+        converter.update_rates(rates);
+        assert_eq!(converter.supported_currencies(), vec!["GBP", "USD"]);
+    }
+
+    #[test]
+    fn test_unknown_currency() {
+        let converter = CurrencyConverter::offline();
+        assert!(converter.convert(1.0, &c("USD"), &c("XYZ")).is_err());
+    }
+
+    #[test]
+    fn test_fee_is_deducted() {
+        let mut converter = CurrencyConverter::offline();
+        converter.set_fee(2.0);
+        let (net, fee) = converter.convert_with_fee(100.0, &c("USD"), &c("INR")).unwrap();
+        assert!((net - 8183.0).abs() < 1e-9);
+        assert!((fee - 167.0).abs() < 1e-9);
+        assert_eq!(converter.convert(100.0, &c("USD"), &c("INR")).unwrap(), net);
+    }
+
+    #[test]
+    fn test_offline_env_values() {
+        assert!(is_truthy(Some("1")));
+        assert!(is_truthy(Some("TRUE")));
+        assert!(!is_truthy(Some("0")));
+        assert!(!is_truthy(Some("")));
+        assert!(!is_truthy(None));
+    }
+
+    #[test]
+    fn test_rate_override() {
+        let mut converter = CurrencyConverter::offline();
+        converter.set_rate(&c("USD"), &c("INR"), 84.0);
+        assert_eq!(converter.convert(2.0, &c("USD"), &c("INR")).unwrap(), 168.0);
+        assert_eq!(converter.convert(168.0, &c("INR"), &c("USD")).unwrap(), 2.0);
+        // Other pairs still use the regular rates
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("EUR")).unwrap(), 0.92);
+
+        assert!(converter.clear_rate(&c("INR"), &c("USD")));
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("INR")).unwrap(), 83.5);
+        assert!(!converter.clear_rate(&c("USD"), &c("INR")));
+    }
+
+    #[test]
+    fn test_currency_normalizes_codes_and_symbols() {
+        for (input, code) in [("USD", "USD"), ("usd", "USD"), ("Eur.", "EUR"), ("$", "USD"), ("€", "EUR"), ("₹", "INR"), ("pkr", "PKR")] {
+            assert_eq!(Currency::new(input).unwrap().code(), code, "{:?}", input);
+        }
+        assert_eq!(c("inr").to_string(), "INR");
+        assert_eq!(c("inr"), "INR");
+    }
+
+    #[test]
+    fn test_currency_rejects_invalid_codes() {
+        for input in ["", "US", "USDT", "U5D", "£", "dollars"] {
+            assert_eq!(Currency::new(input), Err(format!("Invalid currency: {}", input)));
+        }
+    }
+
+    // Answers every request with the same rates, like the real API
+    fn serve_rates(body: &'static str) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/latest/USD", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 4096]);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_updater_refreshes_shared_converter() {
+        let url = serve_rates(r#"{"rates": {"USD": 1.0, "INR": 90.0}}"#);
+        let converter = Arc::new(RwLock::new(CurrencyConverter::offline()));
+        converter.write().unwrap().set_rate(&c("USD"), &c("EUR"), 0.5);
+
+        let handle = CurrencyConverter::spawn_updater_from(Arc::clone(&converter), url, Duration::from_millis(10));
+        for _ in 0..500 {
+            if converter.read().unwrap().version() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.abort();
 
-        Ok(result)
+        let converter = converter.read().unwrap();
+        assert!(converter.version() > 0);
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("INR")).unwrap(), 90.0);
+        // Pinned rates survive the update
+        assert_eq!(converter.convert(1.0, &c("USD"), &c("EUR")).unwrap(), 0.5);
     }
 }
@@ -1,6 +1,19 @@
-use crossterm::event::{KeyCode, KeyEvent};
-use crate::calc::Calculator;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crate::calc::{continues, logical_line, Calculator, ResultKind, Settings};
+use crate::completion::completions;
+use crate::value::Value;
 use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+/// File format written by `Editor::export_report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Markdown,
+}
 
 #[derive(Debug)]
 pub struct Editor {
@@ -8,19 +21,347 @@ pub struct Editor {
     pub cursor_line: usize,
     pub cursor_col: usize,
     pub calculator: RefCell<Calculator>,
+    /// Longest a line can be typed, in characters; further keys are ignored. Unbounded if `None`.
+    pub max_line_length: Option<usize>,
+    rates_version: u64,
+    // Previously entered lines, oldest first, and which one is being recalled
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    // Where a Shift+arrow selection started, as (line, column); the cursor is its other end
+    selection_anchor: Option<(usize, usize)>,
+    results_cache: RefCell<ResultsCache>,
+}
+
+/// Most input history entries kept, in memory and on disk; the oldest are dropped first
+pub const HISTORY_LIMIT: usize = 1000;
+
+// The last results of the whole sheet, with what they were computed from
+#[derive(Debug, Default)]
+struct ResultsCache {
+    lines: Vec<String>,
+    rates_version: u64,
+    // As they were after evaluating, since the sheet's commands can change them
+    settings: Option<Settings>,
+    results: Vec<Option<String>>,
+    kinds: Vec<Option<ResultKind>>,
+    warnings: Vec<Option<String>>,
+    evaluations: usize,
 }
 
 impl Editor {
     pub fn new(calculator: Calculator) -> Self {
+        let rates_version = calculator.rates_version();
         Self {
             lines: vec![String::new()],
             cursor_line: 0,
             cursor_col: 0,
             calculator: RefCell::new(calculator),
+            max_line_length: None,
+            rates_version,
+            history: Vec::new(),
+            history_pos: None,
+            selection_anchor: None,
+            results_cache: RefCell::new(ResultsCache::default()),
+        }
+    }
+
+    /// Every line's result, as `Calculator::evaluate_all` gives them. The sheet is only
+    /// evaluated again once a line, a setting or the exchange rates change, so redrawing
+    /// an unchanged sheet costs nothing.
+    pub fn results(&self) -> Vec<Option<String>> {
+        self.refresh_results();
+        self.results_cache.borrow().results.clone()
+    }
+
+    /// The kind of every line's result, matching `results` line for line
+    pub fn result_kinds(&self) -> Vec<Option<ResultKind>> {
+        self.refresh_results();
+        self.results_cache.borrow().kinds.clone()
+    }
+
+    /// Every line's warning, such as a word read as a multiplier, matching `results` line
+    /// for line. Kept apart from the results so they don't end up in exports.
+    pub fn result_warnings(&self) -> Vec<Option<String>> {
+        self.refresh_results();
+        self.results_cache.borrow().warnings.clone()
+    }
+
+    fn refresh_results(&self) {
+        let mut cache = self.results_cache.borrow_mut();
+        let mut calculator = self.calculator.borrow_mut();
+        let rates_version = calculator.rates_version();
+        let fresh = cache.lines == self.lines
+            && cache.rates_version == rates_version
+            && cache.settings.as_ref() == Some(calculator.settings());
+        if !fresh {
+            cache.results = calculator.evaluate_all(&self.lines);
+            cache.kinds = calculator.line_kinds().to_vec();
+            cache.warnings = calculator.line_warnings().to_vec();
+            cache.lines = self.lines.clone();
+            cache.rates_version = rates_version;
+            cache.settings = Some(calculator.settings().clone());
+            cache.evaluations += 1;
+        }
+    }
+
+    /// How many times `results` has evaluated the sheet
+    pub fn evaluations(&self) -> usize {
+        self.results_cache.borrow().evaluations
+    }
+
+    /// Start over with a single empty line. Input history is kept, so earlier lines can
+    /// still be recalled with Up.
+    pub fn clear_buffer(&mut self) {
+        self.lines = vec![String::new()];
+        self.cursor_line = 0;
+        self.cursor_col = 0;
+        self.history_pos = None;
+        self.selection_anchor = None;
+    }
+
+    /// Replace the buffer with the contents of a file, one buffer line per file line
+    pub fn load_from_path(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+
+        self.lines = lines;
+        self.cursor_line = 0;
+        self.cursor_col = 0;
+        Ok(())
+    }
+
+    /// Put the input history saved by `save_history` before this session's, so Up recalls
+    /// lines from earlier sessions too
+    pub fn load_history(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let session = std::mem::take(&mut self.history);
+        for line in contents.lines().map(str::to_string).chain(session) {
+            self.record_history(line);
+        }
+        self.history_pos = None;
+        Ok(())
+    }
+
+    /// Write the input history, one entry per line, for `load_history` in a later session
+    pub fn save_history(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut contents = self.history.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)
+    }
+
+    // Add an entry unless it repeats the last one, dropping the oldest past HISTORY_LIMIT
+    fn record_history(&mut self, line: String) {
+        if line.trim().is_empty() || self.history.last() == Some(&line) {
+            return;
+        }
+        self.history.push(line);
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+    }
+
+    /// Result shown for the cursor line, without any hint lines. None for blank lines, errors
+    /// and incomplete lines.
+    pub fn result_for_current_line(&self) -> Option<String> {
+        // Earlier lines can define variables the cursor line uses, so evaluate the sheet up to it
+        let results = self
+            .calculator
+            .borrow_mut()
+            .evaluate_all(&self.lines[..=self.cursor_line]);
+        first_result_line(results.into_iter().last()??)
+    }
+
+    /// The whole results column as text, one line per input line so it lines up with the
+    /// sheet when pasted. Blank lines stay blank, errors keep their message, and hints such as
+    /// the inverse rate are left out.
+    pub fn results_text(&self) -> String {
+        self.results()
+            .into_iter()
+            .map(|result| result.and_then(|result| result.lines().next().map(str::to_string)).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Start and end of the selection as (line, column), in text order. None when nothing
+    /// is selected.
+    pub fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.selection_anchor?;
+        let cursor = (self.cursor_line, self.cursor_col);
+        match anchor.cmp(&cursor) {
+            std::cmp::Ordering::Less => Some((anchor, cursor)),
+            std::cmp::Ordering::Greater => Some((cursor, anchor)),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    /// The selected bytes of line `idx`
+    pub fn selection_in_line(&self, idx: usize) -> Option<Range<usize>> {
+        let ((start_line, start_col), (end_line, end_col)) = self.selection()?;
+        if idx < start_line || idx > end_line {
+            return None;
+        }
+        let start = if idx == start_line { start_col } else { 0 };
+        let end = if idx == end_line { end_col } else { self.lines[idx].len() };
+        Some(start..end)
+    }
+
+    /// The selected text, with a newline between lines
+    pub fn selected_text(&self) -> Option<String> {
+        let ((start_line, _), (end_line, _)) = self.selection()?;
+        let parts: Vec<&str> = (start_line..=end_line)
+            .filter_map(|idx| Some(&self.lines[idx][self.selection_in_line(idx)?]))
+            .collect();
+        Some(parts.join("\n"))
+    }
+
+    /// Result of just the selected text, for a quick sub-calculation within a longer line.
+    /// A selection over several lines is read as one expression, and names defined above it
+    /// can be used. None when nothing is selected or the selection doesn't evaluate.
+    pub fn evaluate_selection(&self) -> Option<String> {
+        let text = self.selected_text()?;
+        let expression: Vec<&str> = text.lines().map(str::trim).collect();
+        let ((start_line, _), _) = self.selection()?;
+
+        let mut sheet = self.lines[..start_line].to_vec();
+        sheet.push(expression.join(" "));
+        let results = self.calculator.borrow_mut().evaluate_all(&sheet);
+        first_result_line(results.into_iter().last()??)
+    }
+
+    /// Replace the selection with its result, leaving the cursor after it. Returns whether
+    /// anything changed.
+    pub fn replace_selection_with_result(&mut self) -> bool {
+        let Some(result) = self.evaluate_selection() else {
+            return false;
+        };
+        let Some(((start_line, start_col), (end_line, end_col))) = self.selection() else {
+            return false;
+        };
+
+        let value = bare_value(&result);
+        let rest = self.lines[end_line][end_col..].to_string();
+        self.lines[start_line].replace_range(start_col.., &format!("{}{}", value, rest));
+        self.lines.drain(start_line + 1..=end_line);
+        self.cursor_line = start_line;
+        self.cursor_col = start_col + value.len();
+        self.selection_anchor = None;
+        true
+    }
+
+    /// Replace the cursor line with its value, for building on a value instead of the
+    /// expression that gave it. The value is written out in full, such as `3.3333333333333335`
+    /// or `100 USD`, so the line still evaluates to the same thing, and `name = ` or `name: `
+    /// is kept so the name stays defined. Lines that fail, are blank, are commands or are
+    /// joined with `\` are left alone; returns whether the line changed.
+    pub fn collapse_current_line(&mut self) -> bool {
+        let joined = continues(&self.lines[self.cursor_line])
+            || self.cursor_line > 0 && continues(&self.lines[self.cursor_line - 1]);
+        if joined {
+            return false;
+        }
+        let value = {
+            let mut calculator = self.calculator.borrow_mut();
+            calculator.evaluate_all(&self.lines[..=self.cursor_line]);
+            calculator.line_values().last().cloned().flatten()
+        };
+        let Some(value) = value.as_ref().and_then(value_literal) else {
+            return false;
+        };
+
+        let line = &self.lines[self.cursor_line];
+        let collapsed = match definition_prefix(line) {
+            Some(prefix) => format!("{} {}", prefix.trim_end(), value),
+            None => value.to_string(),
+        };
+        self.lines[self.cursor_line] = collapsed;
+        self.move_end();
+        true
+    }
+
+    /// Whether the cursor line parses. A line continued with `\` is checked once its
+    /// last part is written, together with the lines before it.
+    pub fn current_line_is_valid(&self) -> bool {
+        match logical_line(&self.lines, self.cursor_line) {
+            Some(line) => self.calculator.borrow().validate_line(&line).is_ok(),
+            None => true,
         }
     }
 
+    /// The bytes of the cursor line to blame when it doesn't parse, e.g. to underline them.
+    /// Empty at the end of the line when more input was expected. None when the line parses,
+    /// or when the problem is on an earlier line that it continues.
+    pub fn current_line_error_span(&self) -> Option<Range<usize>> {
+        let line = logical_line(&self.lines, self.cursor_line)?;
+        let span = self.calculator.borrow().validate_line(&line).err()?.span;
+
+        // The cursor line is the last part of the joined expression
+        let own = self.lines[self.cursor_line].trim_end();
+        let own = own.strip_suffix('\\').unwrap_or(own);
+        let offset = line.len() - own.len();
+        (span.start >= offset).then(|| span.start - offset..span.end - offset)
+    }
+
+    /// Write every non-blank line with its result as a two-column report.
+    /// Lines that fail to evaluate get their error text in the result column.
+    pub fn export_report(&self, path: impl AsRef<Path>, format: ReportFormat) -> io::Result<()> {
+        fs::write(path, self.render_report(format))
+    }
+
+    pub fn render_report(&self, format: ReportFormat) -> String {
+        let results = self.results();
+        let rows = self
+            .lines
+            .iter()
+            .zip(results)
+            .filter(|(line, _)| !line.trim().is_empty())
+            .map(|(line, result)| (line.trim(), result.unwrap_or_default()));
+
+        let mut report = String::new();
+        match format {
+            ReportFormat::Csv => {
+                report.push_str("Input,Result\n");
+                for (input, result) in rows {
+                    report.push_str(&format!("{},{}\n", csv_field(input), csv_field(&result)));
+                }
+            }
+            ReportFormat::Markdown => {
+                report.push_str("| Input | Result |\n|---|---|\n");
+                for (input, result) in rows {
+                    report.push_str(&format!("| {} | {} |\n", markdown_cell(input), markdown_cell(&result)));
+                }
+            }
+        }
+        report
+    }
+
+    /// True once per exchange-rate update, meaning conversion results are stale
+    pub fn rates_changed(&mut self) -> bool {
+        let current = self.calculator.borrow().rates_version();
+        let changed = current != self.rates_version;
+        self.rates_version = current;
+        changed
+    }
+
     pub fn handle_key(&mut self, key: KeyEvent) {
+        // Any key other than Up/Down ends history recall, keeping the recalled text
+        if !matches!(key.code, KeyCode::Up | KeyCode::Down) {
+            self.history_pos = None;
+        }
+
+        // Shift with a movement key selects from where the cursor was; any other key ends the selection
+        let moves = matches!(
+            key.code,
+            KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down | KeyCode::Home | KeyCode::End
+        );
+        if moves && key.modifiers.contains(KeyModifiers::SHIFT) {
+            self.selection_anchor.get_or_insert((self.cursor_line, self.cursor_col));
+        } else {
+            self.selection_anchor = None;
+        }
+
         match key.code {
             KeyCode::Char(c) => self.insert_char(c),
             KeyCode::Backspace => self.backspace(),
@@ -32,12 +373,55 @@ impl Editor {
             KeyCode::Down => self.move_down(),
             KeyCode::Home => self.move_home(),
             KeyCode::End => self.move_end(),
+            KeyCode::Tab => self.accept_completion(),
             _ => {}
         }
     }
 
+    /// Start column and text of the identifier being typed just before the cursor
+    pub fn word_before_cursor(&self) -> Option<(usize, &str)> {
+        let line = &self.lines[self.cursor_line];
+        let before = &line[..self.cursor_col];
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| c.is_alphanumeric() || *c == '_')
+            .last()
+            .map(|(idx, _)| idx)?;
+
+        let word = &before[start..];
+        // Numbers like `100` aren't identifiers
+        if !word.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        Some((start, word))
+    }
+
+    pub fn completions(&self) -> Vec<String> {
+        match self.word_before_cursor() {
+            Some((_, word)) => completions(word, &self.calculator.borrow()),
+            None => Vec::new(),
+        }
+    }
+
+    fn accept_completion(&mut self) {
+        let Some((start, _)) = self.word_before_cursor() else {
+            return;
+        };
+        let Some(candidate) = self.completions().into_iter().next() else {
+            return;
+        };
+
+        let line = &mut self.lines[self.cursor_line];
+        line.replace_range(start..self.cursor_col, &candidate);
+        self.cursor_col = start + candidate.len();
+    }
+
     fn insert_char(&mut self, c: char) {
         let line = &mut self.lines[self.cursor_line];
+        if self.max_line_length.is_some_and(|max| line.chars().count() >= max) {
+            return;
+        }
         line.insert(self.cursor_col, c);
         self.cursor_col += 1;
     }
@@ -68,6 +452,7 @@ impl Editor {
     }
 
     fn new_line(&mut self) {
+        self.record_history(self.lines[self.cursor_line].clone());
         let line = &self.lines[self.cursor_line];
         let remainder = line[self.cursor_col..].to_string();
         self.lines[self.cursor_line].truncate(self.cursor_col);
@@ -95,7 +480,29 @@ impl Editor {
         }
     }
 
+    // Recall is available on the last line while it's empty, or while already recalling
+    fn in_history_mode(&self) -> bool {
+        let on_last_line = self.cursor_line == self.lines.len() - 1;
+        on_last_line && (self.history_pos.is_some() || self.lines[self.cursor_line].is_empty())
+    }
+
+    fn recall_history(&mut self, pos: Option<usize>) {
+        self.history_pos = pos;
+        let text = pos.map(|p| self.history[p].clone()).unwrap_or_default();
+        self.cursor_col = text.len();
+        self.lines[self.cursor_line] = text;
+    }
+
     fn move_up(&mut self) {
+        if self.in_history_mode() && !self.history.is_empty() {
+            let pos = match self.history_pos {
+                None => self.history.len() - 1,
+                Some(p) => p.saturating_sub(1),
+            };
+            self.recall_history(Some(pos));
+            return;
+        }
+
         if self.cursor_line > 0 {
             self.cursor_line -= 1;
             let line_len = self.lines[self.cursor_line].len();
@@ -106,6 +513,13 @@ impl Editor {
     }
 
     fn move_down(&mut self) {
+        if let Some(p) = self.history_pos {
+            // Moving past the newest entry returns to an empty line
+            let next = (p + 1 < self.history.len()).then_some(p + 1);
+            self.recall_history(next);
+            return;
+        }
+
         if self.cursor_line < self.lines.len() - 1 {
             self.cursor_line += 1;
             let line_len = self.lines[self.cursor_line].len();
@@ -123,3 +537,573 @@ impl Editor {
         self.cursor_col = self.lines[self.cursor_line].len();
     }
 }
+
+// The first line of a result, or None for errors and lines still being typed
+fn first_result_line(result: String) -> Option<String> {
+    if result.starts_with("Error:") || result.starts_with("Parse error:") || result == "…" {
+        return None;
+    }
+    result.lines().next().map(str::to_string)
+}
+
+// A result without the estimate or fee in brackets after it, so it reads back as the same value
+fn bare_value(result: &str) -> &str {
+    result.split(" (").next().unwrap_or(result)
+}
+
+// `value` as text that reads back as the same value, None when there's no such text
+fn value_literal(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) => Some(n.to_string()),
+        Value::BigInt(n) => Some(n.to_string()),
+        Value::Currency(amount, currency) => Some(format!("{} {}", amount, currency)),
+        // `60 km/h` would read back as a division by `h`
+        Value::Unit(_, unit) if unit.contains('/') => None,
+        Value::Unit(amount, unit) => Some(format!("{} {}", amount, unit)),
+        Value::Bool(_) => None,
+    }
+}
+
+// `rent =` or `rent:` at the start of a line that defines a name
+fn definition_prefix(line: &str) -> Option<&str> {
+    let idx = line.find(['=', ':'])?;
+    let name = line[..idx].trim();
+    let is_identifier = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    (is_identifier && !line[idx + 1..].starts_with('=')).then(|| &line[..=idx])
+}
+
+/// Byte position of the parenthesis matching the one at byte `col`, if `col` is on a
+/// parenthesis and it has a partner
+pub fn match_paren(line: &str, col: usize) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let (open, close, forward) = match bytes.get(col)? {
+        b'(' => (b'(', b')', true),
+        b')' => (b')', b'(', false),
+        _ => return None,
+    };
+
+    let mut depth = 0;
+    let positions: Box<dyn Iterator<Item = usize>> = if forward {
+        Box::new(col..bytes.len())
+    } else {
+        Box::new((0..=col).rev())
+    };
+    for pos in positions {
+        if bytes[pos] == open {
+            depth += 1;
+        } else if bytes[pos] == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(pos);
+            }
+        }
+    }
+    None
+}
+
+/// Byte positions of parentheses without a partner, in order
+pub fn unbalanced_positions(line: &str) -> Vec<usize> {
+    let mut open = Vec::new();
+    let mut unbalanced = Vec::new();
+    for (pos, byte) in line.bytes().enumerate() {
+        match byte {
+            b'(' => open.push(pos),
+            b')' if open.pop().is_none() => unbalanced.push(pos),
+            _ => {}
+        }
+    }
+    unbalanced.extend(open);
+    unbalanced.sort_unstable();
+    unbalanced
+}
+
+// Quote fields containing separators, e.g. grouped numbers like 1,500
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', "<br>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+    use crate::currency::CurrencyConverter;
+    use crate::calc::AssignmentEcho;
+    use std::collections::HashMap;
+
+    fn create_test_editor() -> Editor {
+        Editor::new(Calculator::with_converter(CurrencyConverter::offline()))
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode) {
+        editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    fn type_line(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            press(editor, KeyCode::Char(c));
+        }
+        press(editor, KeyCode::Enter);
+    }
+
+    #[test]
+    fn test_history_recall_cycles_up_and_down() {
+        let mut editor = create_test_editor();
+        type_line(&mut editor, "1 + 1");
+        type_line(&mut editor, "2 * 3");
+
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.lines[2], "2 * 3");
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.lines[2], "1 + 1");
+        // Stays on the oldest entry
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.lines[2], "1 + 1");
+        assert_eq!(editor.cursor_line, 2);
+
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.lines[2], "2 * 3");
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.lines[2], "");
+    }
+
+    #[test]
+    fn test_max_line_length_stops_insertion() {
+        let mut editor = create_test_editor();
+        editor.max_line_length = Some(5);
+        for c in "1234567".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.lines[0], "12345");
+        assert_eq!(editor.cursor_col, 5);
+
+        // Room again after deleting, and other lines have their own limit
+        press(&mut editor, KeyCode::Backspace);
+        press(&mut editor, KeyCode::Char('9'));
+        assert_eq!(editor.lines[0], "12349");
+        press(&mut editor, KeyCode::Enter);
+        type_line(&mut editor, "abcdefg");
+        assert_eq!(editor.lines[1], "abcde");
+    }
+
+    #[test]
+    fn test_lines_are_unbounded_by_default() {
+        let mut editor = create_test_editor();
+        let long = "1".repeat(10_000);
+        for c in long.chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.lines[0], long);
+    }
+
+    #[test]
+    fn test_history_recall_ends_on_edit() {
+        let mut editor = create_test_editor();
+        type_line(&mut editor, "10 k");
+
+        press(&mut editor, KeyCode::Up);
+        press(&mut editor, KeyCode::Char('!'));
+        assert_eq!(editor.lines[1], "10 k!");
+        assert_eq!(editor.cursor_col, 5);
+
+        // The line has content now, so Up moves between lines again
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.cursor_line, 0);
+    }
+
+    #[test]
+    fn test_up_navigates_normally_with_content() {
+        let mut editor = create_test_editor();
+        type_line(&mut editor, "5");
+        press(&mut editor, KeyCode::Char('6'));
+
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.cursor_line, 0);
+        assert_eq!(editor.lines, vec!["5", "6"]);
+    }
+
+    #[test]
+    fn test_blank_lines_are_not_recorded() {
+        let mut editor = create_test_editor();
+        press(&mut editor, KeyCode::Enter);
+
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.cursor_line, 0);
+        assert!(editor.history.is_empty());
+    }
+
+    #[test]
+    fn test_repeated_lines_are_recorded_once() {
+        let mut editor = create_test_editor();
+        type_line(&mut editor, "1 + 1");
+        type_line(&mut editor, "1 + 1");
+        type_line(&mut editor, "2 * 3");
+        type_line(&mut editor, "1 + 1");
+        assert_eq!(editor.history, vec!["1 + 1", "2 * 3", "1 + 1"]);
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut editor = create_test_editor();
+        for n in 0..HISTORY_LIMIT + 5 {
+            editor.record_history(n.to_string());
+        }
+        assert_eq!(editor.history.len(), HISTORY_LIMIT);
+        assert_eq!(editor.history[0], "5");
+    }
+
+    #[test]
+    fn test_history_save_and_load() {
+        let path = std::env::temp_dir().join(format!("indumi_history_{}", std::process::id()));
+        let mut editor = create_test_editor();
+        type_line(&mut editor, "100 USD to INR");
+        type_line(&mut editor, "rent = 1500");
+        editor.save_history(&path).unwrap();
+
+        // A new session recalls the saved lines, older than its own
+        let mut next = create_test_editor();
+        type_line(&mut next, "rent = 1500");
+        type_line(&mut next, "rent * 12");
+        next.load_history(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(next.history, vec!["100 USD to INR", "rent = 1500", "rent * 12"]);
+
+        press(&mut next, KeyCode::Up);
+        press(&mut next, KeyCode::Up);
+        press(&mut next, KeyCode::Up);
+        assert_eq!(next.lines[2], "100 USD to INR");
+
+        assert!(create_test_editor().load_history(&path).is_err());
+    }
+
+    fn sheet_editor() -> Editor {
+        let mut editor = create_test_editor();
+        editor.lines = vec![
+            "rent = 1500".to_string(),
+            "".to_string(),
+            "rent * 12".to_string(),
+            "rent / food".to_string(),
+        ];
+        editor
+    }
+
+    #[test]
+    fn test_csv_report() {
+        assert_eq!(
+            sheet_editor().render_report(ReportFormat::Csv),
+            "Input,Result\n\
+             rent = 1500,\"1,500 (1.5 K)\"\n\
+             rent * 12,\"18,000 (18 K)\"\n\
+             rent / food,Error: Undefined variable: food\n"
+        );
+    }
+
+    #[test]
+    fn test_markdown_report() {
+        assert_eq!(
+            sheet_editor().render_report(ReportFormat::Markdown),
+            "| Input | Result |\n|---|---|\n\
+             | rent = 1500 | 1,500 (1.5 K) |\n\
+             | rent * 12 | 18,000 (18 K) |\n\
+             | rent / food | Error: Undefined variable: food |\n"
+        );
+    }
+
+    #[test]
+    fn test_export_report_writes_file() {
+        let path = std::env::temp_dir().join(format!("indumi_report_{}.csv", std::process::id()));
+        let editor = sheet_editor();
+        editor.export_report(&path, ReportFormat::Csv).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, editor.render_report(ReportFormat::Csv));
+    }
+
+    #[test]
+    fn test_result_for_current_line() {
+        let mut editor = sheet_editor();
+        assert_eq!(editor.result_for_current_line(), Some("1,500 (1.5 K)".to_string()));
+
+        // Uses the variable defined above the cursor
+        editor.cursor_line = 2;
+        assert_eq!(editor.result_for_current_line(), Some("18,000 (18 K)".to_string()));
+
+        // Blank lines and errors have nothing to copy
+        editor.cursor_line = 1;
+        assert_eq!(editor.result_for_current_line(), None);
+        editor.cursor_line = 3;
+        assert_eq!(editor.result_for_current_line(), None);
+    }
+
+    #[test]
+    fn test_results_text() {
+        let mut editor = sheet_editor();
+        editor.lines.push("100 USD to INR".to_string());
+        editor.lines.insert(0, "inverse on".to_string());
+        assert_eq!(
+            editor.results_text(),
+            "Inverse rates on\n1,500 (1.5 K)\n\n18,000 (18 K)\nError: Undefined variable: food\n₹ 8,350 (8.3 K)"
+        );
+    }
+
+    #[test]
+    fn test_result_warnings() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["k = 3".to_string(), "10 k".to_string()];
+        assert_eq!(editor.results()[1].as_deref(), Some("10,000 (10 K)"));
+        assert_eq!(
+            editor.result_warnings(),
+            vec![None, Some("k read as a multiplier, not the variable k".to_string())]
+        );
+        // Exports carry the result alone
+        assert_eq!(editor.results_text(), "3\n10,000 (10 K)");
+    }
+
+    #[test]
+    fn test_clear_buffer() {
+        let mut editor = sheet_editor();
+        editor.cursor_line = 2;
+        editor.cursor_col = 4;
+        editor.clear_buffer();
+        assert_eq!(editor.lines, vec![String::new()]);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 0));
+    }
+
+    #[test]
+    fn test_collapse_current_line() {
+        let mut editor = sheet_editor();
+        editor.lines[2] = "rent * 12 + 0.5".to_string();
+        editor.cursor_line = 2;
+        editor.cursor_col = 0;
+        assert!(editor.collapse_current_line());
+        assert_eq!(editor.lines[2], "18000.5");
+        assert_eq!(editor.cursor_col, editor.lines[2].len());
+        // The collapsed text evaluates to the same value
+        assert_eq!(editor.result_for_current_line(), Some("18,000.50 (18 K)".to_string()));
+
+        // Definitions keep their name, and values their full precision and currency
+        editor.lines[0] = "rent = 1500 / 3 * 2".to_string();
+        editor.cursor_line = 0;
+        assert!(editor.collapse_current_line());
+        assert_eq!(editor.lines[0], "rent = 1000");
+        editor.lines[2] = "fare = 10 USD / 3".to_string();
+        editor.cursor_line = 2;
+        assert!(editor.collapse_current_line());
+        assert_eq!(editor.lines[2], "fare = 3.3333333333333335 USD");
+    }
+
+    #[test]
+    fn test_collapse_current_line_leaves_commands() {
+        let mut editor = create_test_editor();
+        for command in ["grouping off", "describe 5"] {
+            editor.lines = vec![command.to_string()];
+            editor.cursor_line = 0;
+            assert!(!editor.collapse_current_line());
+            assert_eq!(editor.lines[0], command);
+        }
+
+        // The name shown in place of an assignment's value isn't what gets written
+        editor.calculator.borrow_mut().set_assignment_echo(AssignmentEcho::Name);
+        editor.lines = vec!["x = 4".to_string(), "y = x * 2".to_string()];
+        editor.cursor_line = 1;
+        assert!(editor.collapse_current_line());
+        assert_eq!(editor.lines[1], "y = 8");
+    }
+
+    #[test]
+    fn test_collapse_current_line_leaves_errors() {
+        let mut editor = sheet_editor();
+        editor.cursor_line = 3;
+        editor.cursor_col = 2;
+        assert!(!editor.collapse_current_line());
+        assert_eq!(editor.lines[3], "rent / food");
+        assert_eq!(editor.cursor_col, 2);
+
+        // Part of a `\` continuation
+        editor.lines = vec!["100 + \\".to_string(), "200".to_string()];
+        editor.cursor_line = 1;
+        assert!(!editor.collapse_current_line());
+        assert_eq!(editor.lines[1], "200");
+    }
+
+    #[test]
+    fn test_results_are_cached_until_something_changes() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["a = 2".to_string(), "a * 21".to_string()];
+        let results = editor.results();
+        assert_eq!(results[1].as_deref(), Some("42"));
+        assert_eq!(editor.evaluations(), 1);
+
+        // Redrawing, moving the cursor and exporting reuse the results
+        editor.results();
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.results(), results);
+        editor.render_report(ReportFormat::Csv);
+        assert_eq!(editor.evaluations(), 1);
+
+        editor.lines[0] = "a = 3".to_string();
+        assert_eq!(editor.results()[1].as_deref(), Some("63"));
+        assert_eq!(editor.evaluations(), 2);
+    }
+
+    #[test]
+    fn test_forward_references_need_two_pass() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["total = a + 1".to_string(), "a = 5".to_string()];
+        assert_eq!(editor.results()[0].as_deref(), Some("Error: Undefined variable: a"));
+
+        // `a` from the last evaluation doesn't carry over into this one
+        editor.lines[0] = "total = a + 2".to_string();
+        assert_eq!(editor.results()[0].as_deref(), Some("Error: Undefined variable: a"));
+
+        editor.lines.insert(0, "two pass on".to_string());
+        assert_eq!(editor.results()[1].as_deref(), Some("7"));
+        editor.lines.remove(0);
+        assert_eq!(editor.results()[0].as_deref(), Some("Error: Undefined variable: a"));
+    }
+
+    #[test]
+    fn test_results_follow_settings_and_rates() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["1234567".to_string(), "share on".to_string()];
+        assert_eq!(editor.results()[0].as_deref(), Some("1,234,567 (1.2 M) (100%)"));
+        // The sheet's own commands don't count as a change
+        editor.results();
+        assert_eq!(editor.evaluations(), 1);
+
+        editor.calculator.borrow_mut().toggle_number_system();
+        assert_eq!(editor.results()[0].as_deref(), Some("12,34,567 (12.3 Lac) (100%)"));
+        assert_eq!(editor.evaluations(), 2);
+
+        let converter = editor.calculator.borrow().shared_converter();
+        converter.write().unwrap().update_rates(HashMap::from([("USD".to_string(), 1.0)]));
+        editor.results();
+        assert_eq!(editor.evaluations(), 3);
+    }
+
+    fn shift(editor: &mut Editor, code: KeyCode) {
+        editor.handle_key(KeyEvent::new(code, KeyModifiers::SHIFT));
+    }
+
+    #[test]
+    fn test_evaluate_selection() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["rate = 4".to_string(), "(12 * rate) + 100".to_string()];
+        editor.cursor_line = 1;
+        editor.cursor_col = 1;
+        assert_eq!(editor.evaluate_selection(), None);
+
+        for _ in 0..9 {
+            shift(&mut editor, KeyCode::Right);
+        }
+        assert_eq!(editor.selected_text().as_deref(), Some("12 * rate"));
+        assert_eq!(editor.evaluate_selection().as_deref(), Some("48"));
+
+        // Plain movement ends the selection
+        press(&mut editor, KeyCode::Right);
+        assert_eq!(editor.selection(), None);
+    }
+
+    #[test]
+    fn test_evaluate_selection_across_lines() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["x = 1000 +".to_string(), "500".to_string()];
+        editor.cursor_col = 4;
+        shift(&mut editor, KeyCode::Down);
+        shift(&mut editor, KeyCode::End);
+        assert_eq!(editor.selected_text().as_deref(), Some("1000 +\n500"));
+        assert_eq!(editor.evaluate_selection().as_deref(), Some("1,500 (1.5 K)"));
+    }
+
+    #[test]
+    fn test_evaluate_invalid_selection() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["(2 + 3) * 4".to_string()];
+        editor.cursor_col = 3;
+        shift(&mut editor, KeyCode::Right);
+        shift(&mut editor, KeyCode::Right);
+        assert_eq!(editor.selected_text().as_deref(), Some("+ "));
+        assert_eq!(editor.evaluate_selection(), None);
+        assert!(!editor.replace_selection_with_result());
+        assert_eq!(editor.lines[0], "(2 + 3) * 4");
+    }
+
+    #[test]
+    fn test_replace_selection_with_result() {
+        let mut editor = create_test_editor();
+        editor.lines = vec!["(1500 * 2) / 4".to_string()];
+        editor.cursor_col = 10;
+        // Selected backwards, from the cursor to the start of the line
+        shift(&mut editor, KeyCode::Home);
+        assert_eq!(editor.selection(), Some(((0, 0), (0, 10))));
+
+        assert!(editor.replace_selection_with_result());
+        assert_eq!(editor.lines[0], "3,000 / 4");
+        assert_eq!(editor.cursor_col, 5);
+        assert_eq!(editor.selection(), None);
+    }
+
+    #[test]
+    fn test_current_line_is_valid() {
+        let mut editor = sheet_editor();
+        assert!(editor.current_line_is_valid());
+
+        editor.lines = vec!["total = 100 + \\".to_string(), "200 * )".to_string()];
+        // Still being continued, so not checked on its own
+        assert!(editor.current_line_is_valid());
+        editor.cursor_line = 1;
+        assert!(!editor.current_line_is_valid());
+        editor.lines[1] = "200".to_string();
+        assert!(editor.current_line_is_valid());
+    }
+
+    #[test]
+    fn test_current_line_error_span() {
+        let mut editor = sheet_editor();
+        editor.lines = vec!["rent: 1500 + )".to_string()];
+        editor.cursor_line = 0;
+        assert_eq!(editor.current_line_error_span(), Some(13..14));
+
+        // Measured from the start of the cursor line, not the joined expression
+        editor.lines = vec!["total = 100 + \\".to_string(), "200 * )".to_string()];
+        editor.cursor_line = 1;
+        assert_eq!(editor.current_line_error_span(), Some(6..7));
+        editor.lines = vec!["total = ) + \\".to_string(), "200".to_string()];
+        assert_eq!(editor.current_line_error_span(), None);
+
+        editor.lines = vec!["200".to_string()];
+        editor.cursor_line = 0;
+        assert_eq!(editor.current_line_error_span(), None);
+    }
+
+    #[test]
+    fn test_match_paren() {
+        assert_eq!(match_paren("(1 + 2)", 0), Some(6));
+        assert_eq!(match_paren("(1 + 2)", 6), Some(0));
+        // Nested pairs match their own partner
+        assert_eq!(match_paren("((1) + (2))", 0), Some(10));
+        assert_eq!(match_paren("((1) + (2))", 1), Some(3));
+        assert_eq!(match_paren("((1) + (2))", 9), Some(7));
+        // Unmatched, not on a parenthesis, or past the end
+        assert_eq!(match_paren("(1 + 2", 0), None);
+        assert_eq!(match_paren("1 + 2)", 5), None);
+        assert_eq!(match_paren("(1 + 2)", 1), None);
+        assert_eq!(match_paren("(1)", 3), None);
+    }
+
+    #[test]
+    fn test_unbalanced_positions() {
+        assert_eq!(unbalanced_positions("(1 + (2 * 3))"), Vec::<usize>::new());
+        assert_eq!(unbalanced_positions("(1 + 2"), vec![0]);
+        assert_eq!(unbalanced_positions("1 + 2)"), vec![5]);
+        assert_eq!(unbalanced_positions(")(1 + (2)"), vec![0, 1]);
+    }
+}
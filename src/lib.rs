@@ -1,9 +1,17 @@
 // Library exports for testing
 
 pub mod calc;
+pub mod completion;
 pub mod currency;
+pub mod editor;
 pub mod parser;
+pub mod sheet;
+pub mod value;
 
 // Re-export commonly used types
-pub use calc::Calculator;
-pub use parser::{Expression, Operator, Parser};
+pub use calc::{AssignmentEcho, CalcError, Calculator, DivisionByZero, Estimates, Locale, NegativeStyle, NumberSystem, ResultKind, RoundingMode, Settings, SymbolPosition};
+pub use currency::Currency;
+pub use editor::Editor;
+pub use parser::{Expression, Operator, ParseError, Parser, Token};
+pub use sheet::Sheet;
+pub use value::Value;
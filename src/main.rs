@@ -1,61 +1,249 @@
-mod editor;
+mod clipboard;
+mod terminal;
 mod ui;
 
 // Use library modules
-use indumi::{calc, parser, currency};
+use indumi::{calc, editor};
+use indumi::currency::{self, CurrencyConverter};
 
-use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
 use ratatui::{
     backend::CrosstermBackend,
     Terminal,
 };
-use std::io;
+use clap::{ArgGroup, Parser, ValueEnum};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::editor::Editor;
+use crate::editor::{Editor, ReportFormat};
 use crate::calc::Calculator;
+use crate::terminal::TerminalGuard;
 use crate::ui::render_ui;
 
+// Where Ctrl+E writes the sheet and its results
+const REPORT_PATH: &str = "indumi-report.md";
+// Input history kept between sessions, in the home directory
+const HISTORY_FILE: &str = ".indumi_history";
+// How often a running session re-fetches exchange rates
+const RATE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A calculator that feels like a text editor
+#[derive(Parser, Debug)]
+#[command(version, group = ArgGroup::new("non_interactive").args(["batch", "eval"]))]
+struct Cli {
+    /// Sheet to open, one expression per line
+    file: Option<PathBuf>,
+
+    /// Evaluate the file (or stdin) and print the results instead of opening the editor
+    #[arg(long)]
+    batch: bool,
+
+    /// Evaluate one expression, print its result and exit; fails if the expression does
+    #[arg(long, value_name = "EXPR", allow_hyphen_values = true, conflicts_with_all = ["file", "batch"])]
+    eval: Option<String>,
+
+    /// Evaluate a sheet and list the lines that fail; exits non-zero if any do
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["file", "batch", "eval"])]
+    check: Option<PathBuf>,
+
+    /// Use the built-in exchange rates and never touch the network (same as INDUMI_OFFLINE=1)
+    #[arg(long)]
+    no_network: bool,
+
+    /// Read exchange rates from a JSON file shaped like the rates API's response
+    /// (`{"rates": {"USD": 1, ...}}`) instead of fetching them
+    #[arg(long, value_name = "PATH")]
+    rates_file: Option<PathBuf>,
+
+    /// Output format for --batch and --eval
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain, requires = "non_interactive")]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// One result per input line
+    Plain,
+    /// Input and result columns as CSV
+    Csv,
+    /// Input and result columns as a Markdown table
+    Markdown,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
+    // --help and --version print and exit here, before the terminal is touched
+    let cli = Cli::parse();
+
+    if cli.batch || cli.eval.is_some() {
+        return run_batch(&cli).await;
+    }
+    if let Some(path) = &cli.check {
+        return run_check(&cli, path).await;
+    }
+
+    // Setup terminal; the guard restores it on every exit path, panics included
+    let _guard = TerminalGuard::enter()?;
+    let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
-    // Create calculator with currency rates
-    let calculator = Calculator::new().await?;
+    // Create calculator with currency rates, kept fresh in the background when online
+    let calculator = create_calculator(&cli).await?;
+    let updater = (!cli.no_network && cli.rates_file.is_none() && !currency::offline_requested())
+        .then(|| CurrencyConverter::spawn_updater(calculator.shared_converter(), RATE_REFRESH_INTERVAL));
 
     // Create editor state
     let mut editor = Editor::new(calculator);
+    if let Some(path) = &cli.file {
+        // A missing file just means starting with an empty sheet
+        let _ = editor.load_from_path(path);
+    }
+    let history_path = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history_path {
+        // No history yet on the first run
+        let _ = editor.load_history(path);
+    }
 
-    // Main loop
+    // Main loop: redraw after any terminal event, or when new rates make conversions stale
+    let mut needs_redraw = true;
     loop {
-        terminal.draw(|f| {
-            render_ui(f, &editor);
-        })?;
+        if needs_redraw {
+            terminal.draw(|f| {
+                render_ui(f, &editor);
+            })?;
+        }
+        needs_redraw = false;
+
+        if event::poll(Duration::from_millis(250))? {
+            if let Event::Key(key) = event::read()? {
+                if should_quit(&key) {
+                    break;
+                }
+                if is_ctrl(&key, 'e') {
+                    // Nowhere to show an error in the TUI yet; a failed export just leaves no file
+                    let _ = editor.export_report(REPORT_PATH, ReportFormat::Markdown);
+                } else if is_ctrl(&key, 'l') {
+                    editor.clear_buffer();
+                } else if is_ctrl(&key, 'r') {
+                    // A selection is replaced on its own, otherwise the whole line
+                    if editor.selection().is_some() {
+                        editor.replace_selection_with_result();
+                    } else {
+                        editor.collapse_current_line();
+                    }
+                } else if key.code == KeyCode::F(2) {
+                    editor.calculator.borrow_mut().toggle_number_system();
+                } else if is_ctrl(&key, 'y') {
+                    if let Some(result) = editor.result_for_current_line() {
+                        let _ = clipboard::copy(&result);
+                    }
+                } else if is_ctrl(&key, 'k') {
+                    let _ = clipboard::copy(&editor.results_text());
+                } else {
+                    editor.handle_key(key);
+                }
+            }
+            needs_redraw = true;
+        } else if editor.rates_changed() {
+            needs_redraw = true;
+        }
+    }
+
+    if let Some(updater) = updater {
+        updater.abort();
+    }
+    if let Some(path) = &history_path {
+        // Losing the history isn't worth failing the exit over
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+async fn create_calculator(cli: &Cli) -> Result<Calculator, Box<dyn std::error::Error>> {
+    if let Some(path) = &cli.rates_file {
+        Ok(Calculator::with_converter(CurrencyConverter::from_file(path)?))
+    } else if cli.no_network {
+        Ok(Calculator::with_converter(CurrencyConverter::offline()))
+    } else {
+        Calculator::new().await
+    }
+}
+
+// Evaluate a whole sheet (or the one --eval expression) without the TUI and write the
+// results to stdout
+async fn run_batch(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let input = match (&cli.eval, &cli.file) {
+        (Some(expr), _) => expr.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)?,
+        (None, None) => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+
+    let mut editor = Editor::new(create_calculator(cli).await?);
+    editor.lines = input.lines().map(str::to_string).collect();
+    let results = editor.results();
+
+    // Warnings go to stderr, so the results on stdout are the same with or without them
+    for (idx, warning) in editor.result_warnings().iter().enumerate() {
+        if let Some(warning) = warning {
+            eprintln!("line {}: Warning: {}", idx + 1, warning);
+        }
+    }
 
-        if let Event::Key(key) = event::read()? {
-            if should_quit(&key) {
-                break;
+    match cli.format {
+        OutputFormat::Plain => {
+            for result in &results {
+                // Keep one output line per input line, even for multi-line results
+                println!("{}", result.as_deref().unwrap_or_default().replace('\n', "  "));
             }
-            editor.handle_key(key);
         }
+        OutputFormat::Csv => print!("{}", editor.render_report(ReportFormat::Csv)),
+        OutputFormat::Markdown => print!("{}", editor.render_report(ReportFormat::Markdown)),
+    }
+
+    // A script using --eval needs to know the result isn't a number
+    let failed = results.iter().flatten().any(|result| is_failure(result));
+    if cli.eval.is_some() && failed {
+        std::process::exit(1);
     }
+    Ok(())
+}
 
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
+// Print `path:line: error` for every line of the sheet that fails, for linting sheets in CI
+async fn run_check(cli: &Cli, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let lines: Vec<String> = std::fs::read_to_string(path)?.lines().map(str::to_string).collect();
+    let results = create_calculator(cli).await?.evaluate_all(&lines);
 
+    let mut failed = false;
+    for (idx, result) in results.iter().enumerate() {
+        if let Some(result) = result.as_deref().filter(|result| is_failure(result)) {
+            // `…` is what a line still being typed shows; in a saved sheet it's unfinished
+            let error = match result {
+                "…" => "Error: Incomplete expression",
+                result => result.lines().next().unwrap_or_default(),
+            };
+            println!("{}:{}: {}", path.display(), idx + 1, error);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
+fn is_failure(result: &str) -> bool {
+    result.starts_with("Error:") || result.starts_with("Parse error:") || result == "…"
+}
+
 fn should_quit(key: &KeyEvent) -> bool {
-    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+    is_ctrl(key, 'c')
+}
+
+fn is_ctrl(key: &KeyEvent, c: char) -> bool {
+    key.code == KeyCode::Char(c) && key.modifiers.contains(KeyModifiers::CONTROL)
 }
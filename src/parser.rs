@@ -1,4 +1,7 @@
+use crate::currency::Currency;
 use regex::Regex;
+use std::ops::Range;
+use std::sync::LazyLock;
 
 #[derive(Debug, Clone)]
 pub enum Expression {
@@ -6,8 +9,20 @@ pub enum Expression {
     Variable(String),
     BinaryOp { op: Operator, left: Box<Expression>, right: Box<Expression> },
     Assignment { var: String, expr: Box<Expression> },
-    CurrencyAnnotation { value: Box<Expression>, currency: String },
-    CurrencyConversion { source: Box<Expression>, target_currency: String },
+    CurrencyAnnotation { value: Box<Expression>, currency: Currency },
+    // `50 apples`: a free-text unit carried along like a currency
+    UnitAnnotation { value: Box<Expression>, unit: String },
+    CurrencyConversion { source: Box<Expression>, target_currency: Currency },
+    // `100 USD to INR, EUR, GBP`
+    MultiConversion { source: Box<Expression>, target_currencies: Vec<Currency> },
+    // `a < b < c` holds only if every link holds; each operand is evaluated once
+    Comparison { first: Box<Expression>, rest: Vec<(Operator, Expression)> },
+    FunctionCall { name: String, args: Vec<Expression> },
+    // `10% of 500 USD`
+    PercentOf { percent: Box<Expression>, base: Box<Expression> },
+    Factorial(Box<Expression>),
+    // Unary minus: `-x`
+    Negate(Box<Expression>),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -18,30 +33,132 @@ pub enum Operator {
     Divide,
     Power,
     Modulo,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
 }
 
+/// Default limit on nested parentheses, function calls, `% of` chains, unary minus and `^` chains.
+/// Deeper input is rejected instead of risking a stack overflow. Each level costs several
+/// parser frames and `^` and unary minus added more, so 256 no longer fit a 2 MB thread
+/// stack in debug builds; 128 does, with room to spare.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+// `name = expr`, but not `name == expr`. Compiled once and shared by every Parser.
+static ASSIGNMENT_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([a-zA-Z_]\w*)\s*=\s*([^=].*)$").unwrap());
+
+/// Why input doesn't parse, and the byte range of the input to blame: the offending token,
+/// or an empty range at the end when more was expected
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, span: Range<usize>) -> Self {
+        Self { message: message.into(), span }
+    }
+
+    // For an error found in a slice starting `offset` bytes into the input
+    pub(crate) fn shifted(self, offset: usize) -> Self {
+        Self {
+            span: self.span.start + offset..self.span.end + offset,
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Debug)]
 pub struct Parser {
-    assignment_regex: Regex,
+    assignment_regex: &'static Regex,
+    max_depth: usize,
+    // Input written the European way: `3,14` and `1.234,56`
+    decimal_comma: bool,
+    // Digits grouped with spaces, as in `1 234 567`
+    space_grouping: bool,
+    // Spelled-out numbers, as in `two thousand`
+    word_numbers: bool,
 }
 
 impl Parser {
     pub fn new() -> Self {
         Self {
-            assignment_regex: Regex::new(r"^([a-zA-Z_]\w*)\s*=\s*(.+)$").unwrap(),
+            assignment_regex: &ASSIGNMENT_REGEX,
+            max_depth: DEFAULT_MAX_DEPTH,
+            decimal_comma: false,
+            space_grouping: false,
+            word_numbers: false,
         }
     }
 
+    /// Read `,` between digits as the decimal point and `.` between digits as grouping, so
+    /// `1.234,56` is 1234.56. Function arguments are then separated with `;` (or `, `).
+    pub fn set_decimal_comma(&mut self, decimal_comma: bool) {
+        self.decimal_comma = decimal_comma;
+    }
+
+    /// Read a number followed by groups of exactly three digits after single spaces as one
+    /// number, so `1 234 567` is 1234567. `2 cr` and `100 USD` are unaffected.
+    pub fn set_space_grouping(&mut self, space_grouping: bool) {
+        self.space_grouping = space_grouping;
+    }
+
+    /// Read spelled-out numbers such as `two thousand`, `twenty-five` or `five lakh` as their
+    /// values. Off by default, since a word like `one` could otherwise be a variable.
+    pub fn set_word_numbers(&mut self, word_numbers: bool) {
+        self.word_numbers = word_numbers;
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     pub fn parse(&self, input: &str) -> Result<Expression, String> {
+        self.parse_spanned(input).map_err(|e| e.message)
+    }
+
+    fn parse_spanned(&self, input: &str) -> Result<Expression, ParseError> {
         let trimmed = input.trim();
+        let start = input.len() - input.trim_start().len();
 
         if trimmed.is_empty() {
-            return Err("Empty input".to_string());
+            return Err(ParseError::new("Empty input", input.len()..input.len()));
+        }
+
+        // `=2+3` like a spreadsheet formula: the leading `=` just marks an expression
+        if let Some(rest) = trimmed.strip_prefix('=').filter(|rest| !rest.starts_with('=')) {
+            if rest.trim().is_empty() {
+                return Err(ParseError::new("Expected expression after =", input.len()..input.len()));
+            }
+            return self.parse_spanned(rest).map_err(|e| e.shifted(start + 1));
+        }
+
+        // `2 + 3 =` like a desk calculator: the trailing `=` just asks for the result
+        if let Some(rest) = strip_equals_key(trimmed) {
+            return self.parse_spanned(rest).map_err(|e| e.shifted(start));
         }
 
         // Check for assignment
         if let Some(caps) = self.assignment_regex.captures(trimmed) {
             let var = caps[1].to_string();
-            let expr = self.parse(&caps[2])?;
+            if is_reserved(&var) {
+                let span = caps.get(1).unwrap().range();
+                let message = format!("Cannot assign to reserved word: {} ({})", var, reserved_use(&var));
+                return Err(ParseError::new(message, span).shifted(start));
+            }
+            let value = caps.get(2).unwrap();
+            let expr = self.parse_spanned(value.as_str()).map_err(|e| e.shifted(start + value.start()))?;
             return Ok(Expression::Assignment {
                 var,
                 expr: Box::new(expr),
@@ -49,50 +166,190 @@ impl Parser {
         }
 
         // Parse expression (handles everything including currency conversions)
-        self.parse_expression(trimmed)
+        self.parse_expression(trimmed).map_err(|e| e.shifted(start))
     }
 
-    fn parse_expression(&self, input: &str) -> Result<Expression, String> {
-        let tokens = tokenize(input);
+    /// Parse `;`-separated statements such as `a = 5; b = 10; a + b`, in order.
+    /// Only top-level `;` separate statements; empty statements are skipped.
+    pub fn parse_many(&self, input: &str) -> Result<Vec<Expression>, String> {
+        self.parse_many_spanned(input).map_err(|e| e.message)
+    }
+
+    fn parse_many_spanned(&self, input: &str) -> Result<Vec<Expression>, ParseError> {
+        let mut statements = Vec::new();
+        let mut offset = 0;
+        for statement in split_statements(input) {
+            if !statement.trim().is_empty() {
+                statements.push(self.parse_spanned(statement).map_err(|e| e.shifted(offset))?);
+            }
+            // Past the statement and the `;` after it
+            offset += statement.len() + 1;
+        }
+
+        if statements.is_empty() {
+            return Err(ParseError::new("Empty input", input.len()..input.len()));
+        }
+        Ok(statements)
+    }
+
+    /// Check that `input` parses, without keeping the result. Blank input is valid, since
+    /// there's nothing wrong with it yet; `;`-separated statements are checked in turn.
+    pub fn validate(&self, input: &str) -> Result<(), String> {
+        self.check(input).map_err(|e| e.message)
+    }
+
+    /// Like validate, but the error also says which part of `input` is at fault
+    pub fn check(&self, input: &str) -> Result<(), ParseError> {
+        if input.trim().is_empty() {
+            return Ok(());
+        }
+        self.parse_many_spanned(input).map(|_| ())
+    }
+
+    /// Whether `input` fails to parse only because it ends in an operator still waiting for
+    /// its right-hand side, as in `100 +` or `100 USD to`: the user is most likely mid-typing.
+    /// `100 + +` is invalid rather than incomplete, since `100 +` doesn't parse either.
+    pub fn is_incomplete(&self, input: &str) -> bool {
+        let trimmed = input.trim_end();
+        let Some(last) = tokenize(trimmed).pop() else {
+            return false;
+        };
+        if !TRAILING_OPERATORS.iter().any(|op| op.eq_ignore_ascii_case(&last.text)) {
+            return false;
+        }
+
+        let rest = &trimmed[..last.span.start];
+        if rest.trim().is_empty() {
+            // A formula marker waiting for its formula
+            return last.text == "=";
+        }
+        self.parse_many(rest).is_ok()
+    }
+
+    fn parse_expression(&self, input: &str) -> Result<Expression, ParseError> {
+        let spelled = if self.word_numbers {
+            from_number_words(input)
+        } else {
+            input.to_string()
+        };
+        let joined = if self.space_grouping {
+            join_space_groups(&spelled)
+        } else {
+            spelled
+        };
+        let rewritten = if self.decimal_comma {
+            from_decimal_comma(&joined).map_err(|idx| {
+                let span = original_span(input, &char_origins(input, &joined), idx..idx + 1);
+                ParseError::new("'.' groups thousands here; use ',' for the decimal point", span)
+            })?
+        } else {
+            strip_grouping_commas(&joined)
+        };
+        let (tokens, spans, _) = tokenize_spanned(&rewritten);
         if tokens.is_empty() {
-            return Err("No tokens".to_string());
+            return Err(ParseError::new("No tokens", 0..input.len()));
         }
 
         let mut i = 0;
-        self.parse_conversion(&tokens, &mut i)
+        let result = self.parse_comparison(&tokens, &mut i, 0).and_then(|expr| {
+            // Anything left over means the input wasn't fully understood
+            if i < tokens.len() {
+                return Err(format!("Unexpected token: {}", tokens[i]));
+            }
+            Ok(expr)
+        });
+
+        // Parsing stops at the token it couldn't make sense of, or past the end when the input
+        // ran out early
+        result.map_err(|message| {
+            let span = match spans.get(i) {
+                Some(chars) => original_span(input, &char_origins(input, &rewritten), chars.clone()),
+                None => input.len()..input.len(),
+            };
+            ParseError::new(message, span)
+        })
     }
 
-    // Lowest precedence: currency conversion (to operator)
-    fn parse_conversion(&self, tokens: &[String], i: &mut usize) -> Result<Expression, String> {
-        let mut left = self.parse_add_subtract(tokens, i)?;
+    // Lowest precedence: comparisons, so `100 USD to INR > 5000` compares the converted amount.
+    // Chains like `1 < x < 10` collect every link instead of nesting, which keeps `x` evaluated once.
+    fn parse_comparison(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        if depth > self.max_depth {
+            return Err("Expression too deeply nested".to_string());
+        }
+
+        let first = self.parse_conversion(tokens, i, depth)?;
+        let mut rest = Vec::new();
 
-        // Check for "to" operator
-        if *i < tokens.len() && tokens[*i].to_lowercase() == "to" {
+        while *i < tokens.len() {
+            let Some(op) = comparison_operator(&tokens[*i]) else {
+                break;
+            };
+            *i += 1;
+            let right = self.parse_conversion(tokens, i, depth)?;
+            rest.push((op, right));
+        }
+
+        if rest.is_empty() {
+            return Ok(first);
+        }
+
+        Ok(Expression::Comparison {
+            first: Box::new(first),
+            rest,
+        })
+    }
+
+    // Currency conversion (to operator)
+    fn parse_conversion(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let mut left = self.parse_add_subtract(tokens, i, depth)?;
+
+        // Each "to" converts the result so far: `100 USD to EUR to INR` goes through euros.
+        // Converting to several currencies at once can only be the last step.
+        while *i < tokens.len() && tokens[*i].to_lowercase() == "to" && !matches!(left, Expression::MultiConversion { .. }) {
             *i += 1;
             if *i >= tokens.len() {
                 return Err("Expected currency after 'to'".to_string());
             }
 
-            let target_currency = normalize_currency(&tokens[*i]);
+            let mut target_currencies = vec![Currency::new(&tokens[*i])?];
             *i += 1;
 
-            left = Expression::CurrencyConversion {
-                source: Box::new(left),
-                target_currency,
+            // More targets after commas: `to INR, EUR`
+            while *i + 1 < tokens.len() && tokens[*i] == "," && tokens[*i + 1] != "," {
+                *i += 1;
+                target_currencies.push(Currency::new(&tokens[*i])?);
+                *i += 1;
+            }
+
+            // Prose-style punctuation after the target (`100 usd to inr,`) isn't part of the expression
+            if tokens[*i..].iter().all(|token| token == ",") {
+                *i = tokens.len();
+            }
+
+            left = if target_currencies.len() == 1 {
+                Expression::CurrencyConversion {
+                    source: Box::new(left),
+                    target_currency: target_currencies.remove(0),
+                }
+            } else {
+                Expression::MultiConversion {
+                    source: Box::new(left),
+                    target_currencies,
+                }
             };
         }
 
         Ok(left)
     }
 
-    fn parse_add_subtract(&self, tokens: &[String], i: &mut usize) -> Result<Expression, String> {
-        let mut left = self.parse_mul_div(tokens, i)?;
+    fn parse_add_subtract(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let mut left = self.parse_mul_div(tokens, i, depth)?;
 
         while *i < tokens.len() {
             match tokens[*i].as_str() {
                 "+" => {
                     *i += 1;
-                    let right = self.parse_mul_div(tokens, i)?;
+                    let right = self.parse_mul_div(tokens, i, depth)?;
                     left = Expression::BinaryOp {
                         op: Operator::Add,
                         left: Box::new(left),
@@ -101,7 +358,7 @@ impl Parser {
                 }
                 "-" => {
                     *i += 1;
-                    let right = self.parse_mul_div(tokens, i)?;
+                    let right = self.parse_mul_div(tokens, i, depth)?;
                     left = Expression::BinaryOp {
                         op: Operator::Subtract,
                         left: Box::new(left),
@@ -115,29 +372,40 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_mul_div(&self, tokens: &[String], i: &mut usize) -> Result<Expression, String> {
-        let mut left = self.parse_primary(tokens, i)?;
+    fn parse_mul_div(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let mut left = self.parse_percentage(tokens, i, depth)?;
 
         while *i < tokens.len() {
             match tokens[*i].as_str() {
                 "*" => {
                     *i += 1;
-                    let right = self.parse_primary(tokens, i)?;
+                    let right = self.parse_percentage(tokens, i, depth)?;
                     left = Expression::BinaryOp {
                         op: Operator::Multiply,
                         left: Box::new(left),
                         right: Box::new(right),
                     };
                 }
-                "/" => {
+                // `120 km per 2 h` reads as a rate
+                token if token == "/" || token.eq_ignore_ascii_case("per") => {
                     *i += 1;
-                    let right = self.parse_primary(tokens, i)?;
+                    let right = self.parse_percentage(tokens, i, depth)?;
                     left = Expression::BinaryOp {
                         op: Operator::Divide,
                         left: Box::new(left),
                         right: Box::new(right),
                     };
                 }
+                // `%` here is modulo; `10% of x` was already taken by parse_percentage
+                token if token == "%" || token.eq_ignore_ascii_case("mod") => {
+                    *i += 1;
+                    let right = self.parse_percentage(tokens, i, depth)?;
+                    left = Expression::BinaryOp {
+                        op: Operator::Modulo,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    };
+                }
                 _ => break,
             }
         }
@@ -145,7 +413,75 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_primary(&self, tokens: &[String], i: &mut usize) -> Result<Expression, String> {
+    // `10% of x` binds tighter than * and /, so `2 * 10% of 50` is 2 * 5.
+    // A `%` not followed by `of` is left alone for the operators above.
+    fn parse_percentage(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        if depth > self.max_depth {
+            return Err("Expression too deeply nested".to_string());
+        }
+
+        let percent = self.parse_unary(tokens, i, depth)?;
+
+        let is_percent_of = *i + 1 < tokens.len()
+            && tokens[*i] == "%"
+            && tokens[*i + 1].to_lowercase() == "of";
+        if !is_percent_of {
+            return Ok(percent);
+        }
+
+        *i += 2;
+        let base = self.parse_percentage(tokens, i, depth + 1)?;
+        Ok(Expression::PercentOf {
+            percent: Box::new(percent),
+            base: Box::new(base),
+        })
+    }
+
+    // Unary minus binds looser than `^`, so `-2 ^ 2` is -(2 ^ 2)
+    fn parse_unary(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        if depth > self.max_depth {
+            return Err("Expression too deeply nested".to_string());
+        }
+
+        if *i < tokens.len() && tokens[*i] == "-" {
+            *i += 1;
+            let operand = self.parse_unary(tokens, i, depth + 1)?;
+            return Ok(Expression::Negate(Box::new(operand)));
+        }
+
+        self.parse_power(tokens, i, depth)
+    }
+
+    // `^` is right-associative (`2 ^ 3 ^ 2` is 2 ^ 9) and its exponent may be negated: `2 ^ -1`
+    fn parse_power(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let base = self.parse_postfix(tokens, i, depth)?;
+
+        if *i < tokens.len() && tokens[*i] == "^" {
+            *i += 1;
+            let exponent = self.parse_unary(tokens, i, depth + 1)?;
+            return Ok(Expression::BinaryOp {
+                op: Operator::Power,
+                left: Box::new(base),
+                right: Box::new(exponent),
+            });
+        }
+
+        Ok(base)
+    }
+
+    // Postfix factorial: `5!`, `3!!` applies twice
+    fn parse_postfix(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let mut expr = self.parse_primary(tokens, i, depth)?;
+
+        while *i < tokens.len() && tokens[*i] == "!" {
+            *i += 1;
+            expr = Expression::Factorial(Box::new(expr));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_primary(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
         if *i >= tokens.len() {
             return Err("Expected expression".to_string());
         }
@@ -155,7 +491,7 @@ impl Parser {
         // Handle parentheses
         if token == "(" {
             *i += 1;
-            let expr = self.parse_conversion(tokens, i)?;  // Recursive call to top level
+            let expr = self.parse_comparison(tokens, i, depth + 1)?;  // Recursive call to top level
             if *i >= tokens.len() || tokens[*i] != ")" {
                 return Err("Expected closing parenthesis".to_string());
             }
@@ -163,64 +499,599 @@ impl Parser {
             return Ok(expr);
         }
 
+        // A symbol or code written before the amount, as in `$1,234.56` or `USD 100`
+        if is_currency(token) {
+            if let Some(num) = tokens.get(*i + 1).and_then(|next| next.parse::<f64>().ok()) {
+                let currency = Currency::new(token)?;
+                *i += 2;
+                return Ok(Expression::CurrencyAnnotation {
+                    value: Box::new(Expression::Number(num)),
+                    currency,
+                });
+            }
+        }
+
         // Try to parse as number
         if let Ok(num) = token.parse::<f64>() {
             *i += 1;
 
             // Check if next token is a currency code
-            if *i < tokens.len() {
-                if is_currency(&tokens[*i]) {
-                    let currency = normalize_currency(&tokens[*i]);
-                    *i += 1;
-                    return Ok(Expression::CurrencyAnnotation {
-                        value: Box::new(Expression::Number(num)),
-                        currency,
-                    });
-                }
+            if *i < tokens.len() && is_currency(&tokens[*i]) {
+                let currency = Currency::new(&tokens[*i])?;
+                *i += 1;
+                return Ok(Expression::CurrencyAnnotation {
+                    value: Box::new(Expression::Number(num)),
+                    currency,
+                });
+            }
+
+            // A code like `GBP` is a currency the rates don't cover, not a unit
+            if tokens.get(*i).is_some_and(|token| is_unit(token) && looks_like_currency_code(token)) {
+                return Err(format!("Unsupported currency: {}", tokens[*i]));
+            }
+
+            // Any other word right after a number is its unit, unless it calls a function
+            if *i < tokens.len() && is_unit(&tokens[*i]) && tokens.get(*i + 1).is_none_or(|next| next != "(") {
+                let unit = tokens[*i].clone();
+                *i += 1;
+                return Ok(Expression::UnitAnnotation {
+                    value: Box::new(Expression::Number(num)),
+                    unit,
+                });
             }
 
             return Ok(Expression::Number(num));
         }
 
-        // Variable or identifier
+        if is_reserved(token) {
+            return Err(format!("Unexpected keyword: {}", token));
+        }
+
+        // `2e` is read as a number missing its exponent, never as `2 * e`
+        if is_open_exponent(token.trim_end_matches(['+', '-'])) {
+            return Err(format!("Missing exponent in {}", token));
+        }
+
+        // Variable, identifier or function call
         if token.chars().all(|c| c.is_alphanumeric() || c == '_') {
             *i += 1;
+            if *i < tokens.len() && tokens[*i] == "(" {
+                *i += 1;
+                if token.eq_ignore_ascii_case("convert") {
+                    return self.parse_convert(tokens, i, depth + 1);
+                }
+                let args = self.parse_arguments(tokens, i, depth + 1)?;
+                return Ok(Expression::FunctionCall {
+                    name: token.to_lowercase(),
+                    args,
+                });
+            }
             return Ok(Expression::Variable(token.clone()));
         }
 
         Err(format!("Cannot parse: {}", token))
     }
+
+    // `convert(amount, "USD", "INR")`, after the opening parenthesis: the same as
+    // `(amount USD) to INR`, but self-contained so it can sit anywhere in a larger expression
+    fn parse_convert(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Expression, String> {
+        let usage = || "convert expects an amount and two currencies, as in convert(100, \"USD\", \"INR\")".to_string();
+        // A `,` and then a currency, quoted or not
+        let currency_argument = |i: &mut usize| -> Result<Currency, String> {
+            match tokens.get(*i..*i + 2) {
+                Some([comma, code]) if comma == "," => {
+                    *i += 2;
+                    Currency::new(strip_quotes(code))
+                }
+                _ => Err(usage()),
+            }
+        };
+
+        let amount = self.parse_comparison(tokens, i, depth)?;
+        let from = currency_argument(i)?;
+        let to = currency_argument(i)?;
+        match tokens.get(*i).map(String::as_str) {
+            Some(")") => {}
+            None => return Err("Expected closing parenthesis".to_string()),
+            Some(_) => return Err(usage()),
+        }
+        *i += 1;
+
+        Ok(Expression::CurrencyConversion {
+            source: Box::new(Expression::CurrencyAnnotation { value: Box::new(amount), currency: from }),
+            target_currency: to,
+        })
+    }
+
+    // Comma-separated arguments up to and including the closing parenthesis
+    fn parse_arguments(&self, tokens: &[String], i: &mut usize, depth: usize) -> Result<Vec<Expression>, String> {
+        let mut args = Vec::new();
+
+        if *i < tokens.len() && tokens[*i] == ")" {
+            *i += 1;
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.parse_comparison(tokens, i, depth)?);
+
+            if *i >= tokens.len() {
+                return Err("Expected closing parenthesis".to_string());
+            }
+            match tokens[*i].as_str() {
+                "," => *i += 1,
+                ")" => {
+                    *i += 1;
+                    return Ok(args);
+                }
+                other => return Err(format!("Expected ',' or ')' but found: {}", other)),
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-fn tokenize(input: &str) -> Vec<String> {
+// `"USD"` or `'USD'` → `USD`; anything else as it is
+fn strip_quotes(token: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|&quote| token.strip_prefix(quote).and_then(|rest| rest.strip_suffix(quote)))
+        .unwrap_or(token)
+}
+
+// Splits on `;` outside parentheses
+fn split_statements(input: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (idx, ch) in input.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ';' if depth == 0 => {
+                statements.push(&input[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&input[start..]);
+    statements
+}
+
+// The expression before a lone trailing `=`. Not for `x =` (an assignment being typed)
+// or a trailing `==`, `<=`, `>=` or `!=`.
+fn strip_equals_key(input: &str) -> Option<&str> {
+    let rest = input.strip_suffix('=')?.trim_end();
+    let is_identifier = rest.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && rest.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if rest.is_empty() || is_identifier || rest.ends_with(['=', '<', '>', '!']) {
+        return None;
+    }
+    Some(rest)
+}
+
+// `1,234,567.89` → `1234567.89`, so pasted amounts parse. Only well-formed Western or Indian
+// grouping counts. Inside a function call `,` separates arguments, as in `ncr(100,200)`, unless
+// the call separates them with `, `: then `max(1,000, 2)` is 1000 and 2.
+fn strip_grouping_commas(input: &str) -> String {
+    let chars: Vec<char> = input.chars().collect();
+    let spaced = spaced_argument_lists(&chars);
+    let mut result = String::with_capacity(input.len());
+    // For each open parenthesis, whether grouping commas are stripped inside it
+    let mut strips = Vec::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+        let starts_number = ch.is_ascii_digit() && (idx == 0 || !matches!(chars[idx - 1], '0'..='9' | '.' | ','));
+        if starts_number && strips.last().copied().unwrap_or(true) {
+            if let Some(end) = grouped_number_end(&chars, idx) {
+                result.extend(chars[idx..end].iter().filter(|&&c| c != ','));
+                idx = end;
+                continue;
+            }
+        }
+
+        match ch {
+            '(' => strips.push(spaced[idx] || !opens_call(&chars, idx)),
+            ')' => {
+                strips.pop();
+            }
+            _ => {}
+        }
+        result.push(ch);
+        idx += 1;
+    }
+    result
+}
+
+// Whether the `(` at `open` follows a function name, as in `max(` or `log10 (`
+fn opens_call(chars: &[char], open: usize) -> bool {
+    let mut before = chars[..open].iter().rev().skip_while(|c| c.is_whitespace()).peekable();
+    let mut first = None;
+    while let Some(&&c) = before.peek() {
+        if !(c.is_alphanumeric() || c == '_') {
+            break;
+        }
+        first = Some(c);
+        before.next();
+    }
+    first.is_some_and(|c| c.is_alphabetic() || c == '_')
+}
+
+// For each `(`, whether the arguments directly inside it are separated by `, ` rather than `,`
+fn spaced_argument_lists(chars: &[char]) -> Vec<bool> {
+    let mut spaced = vec![false; chars.len()];
+    let mut open = Vec::new();
+    for (idx, &ch) in chars.iter().enumerate() {
+        match ch {
+            '(' => open.push(idx),
+            ')' => {
+                open.pop();
+            }
+            ',' if chars.get(idx + 1).is_some_and(|next| next.is_whitespace()) => {
+                if let Some(&start) = open.last() {
+                    spaced[start] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    spaced
+}
+
+// End of a grouped digit run starting at `start`, if it has at least one comma group: Western
+// `1,234,567` (groups of three) or Indian `12,34,567` (groups of two, then a last three)
+fn grouped_number_end(chars: &[char], start: usize) -> Option<usize> {
+    let digits_from = |pos: usize| chars[pos..].iter().take_while(|c| c.is_ascii_digit()).count();
+
+    let leading = digits_from(start);
+    let mut end = start + leading;
+    let mut groups = Vec::new();
+    while chars.get(end) == Some(&',') && end + 1 < chars.len() && chars[end + 1].is_ascii_digit() {
+        let group = digits_from(end + 1);
+        groups.push(group);
+        end += 1 + group;
+    }
+
+    let (&last, rest) = groups.split_last()?;
+    let western = leading <= 3 && rest.iter().all(|&group| group == 3);
+    let indian = leading <= 2 && rest.iter().all(|&group| group == 2);
+    (last == 3 && (western || indian)).then_some(end)
+}
+
+// `1 234 567` → `1234567`. A group is a single space and exactly three digits with no letter
+// or digit right after, so `2 000 k` is 2000 k but `1 234k` and `10 1000` are left alone.
+fn join_space_groups(input: &str) -> String {
+    let chars: Vec<char> = input.chars().collect();
+    let mut result = String::with_capacity(input.len());
+
+    for (idx, &ch) in chars.iter().enumerate() {
+        if ch == ' ' && idx > 0 && in_space_grouped_number(&chars, idx) {
+            continue;
+        }
+        result.push(ch);
+    }
+    result
+}
+
+// What a word contributes to a spelled-out number
+#[derive(Clone, Copy, PartialEq)]
+enum NumberWord {
+    // zero to nineteen
+    Unit(f64),
+    // twenty to ninety
+    Tens(f64),
+    Hundred,
+    // thousand, lakh, crore, million, billion
+    Scale(f64),
+    // `and`, as in `one hundred and five`
+    And,
+}
+
+fn number_word(word: &str) -> Option<NumberWord> {
+    const UNITS: [&str; 20] = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven", "twelve",
+        "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen",
+    ];
+    const TENS: [&str; 8] = ["twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety"];
+
+    let word = word.to_lowercase();
+    if let Some(n) = UNITS.iter().position(|unit| *unit == word) {
+        return Some(NumberWord::Unit(n as f64));
+    }
+    if let Some(n) = TENS.iter().position(|tens| *tens == word) {
+        return Some(NumberWord::Tens((n as f64 + 2.0) * 10.0));
+    }
+    match word.as_str() {
+        "hundred" => Some(NumberWord::Hundred),
+        "and" => Some(NumberWord::And),
+        // Only the long forms, so `k`, `m` and `cr` keep meaning what they do after digits
+        _ if word.len() > 2 && text_to_multiplier(&word) > 1.0 => Some(NumberWord::Scale(text_to_multiplier(&word))),
+        _ => None,
+    }
+}
+
+// `two thousand five hundred` → `2500`, `twenty-one` → `21`. A run starts at a unit or tens
+// word and takes the words that can follow in a spelled-out number; scales have to get
+// smaller, so `two thousand three thousand` stops before the second `three`.
+fn from_number_words(input: &str) -> String {
+    // Alphabetic words with their byte ranges
+    let mut words = Vec::new();
+    let mut start = None;
+    for (idx, ch) in input.char_indices().chain(std::iter::once((input.len(), ' '))) {
+        match (ch.is_alphabetic(), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(from)) => {
+                words.push(from..idx);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    // Part of an identifier or a call like `one(`
+    let glued = |range: &Range<usize>| {
+        input[..range.start].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_')
+            || input[range.end..].chars().next().is_some_and(|c| c.is_ascii_digit() || c == '_' || c == '(')
+    };
+
+    let mut result = String::with_capacity(input.len());
+    let mut copied = 0;
+    let mut idx = 0;
+    while idx < words.len() {
+        let first = &words[idx];
+        if glued(first) || !matches!(number_word(&input[first.clone()]), Some(NumberWord::Unit(_) | NumberWord::Tens(_))) {
+            idx += 1;
+            continue;
+        }
+
+        let (mut total, mut current) = (0.0, 0.0);
+        let mut last_scale = f64::INFINITY;
+        let mut previous: Option<NumberWord> = None;
+        // One past the last word that belongs to the number, and where that word ends
+        let (mut taken, mut end) = (idx, first.start);
+        // Where the number stood after its last scale word
+        let mut after_scale = (taken, end);
+        let mut next = idx;
+        while let Some(range) = words.get(next) {
+            if next > idx {
+                let gap = &input[words[next - 1].end..range.start];
+                let hyphen = gap == "-" && matches!(previous, Some(NumberWord::Tens(_)));
+                if !hyphen && (gap.is_empty() || !gap.chars().all(char::is_whitespace)) {
+                    break;
+                }
+            }
+            if glued(range) {
+                break;
+            }
+            let Some(word) = number_word(&input[range.clone()]) else { break };
+            let fits = match (previous, word) {
+                (None, _) => true,
+                (Some(NumberWord::Unit(_)), NumberWord::Hundred | NumberWord::Scale(_)) => true,
+                (Some(NumberWord::Tens(_)), NumberWord::Unit(n)) => n > 0.0 && n < 10.0,
+                (Some(NumberWord::Tens(_)), NumberWord::Scale(_)) => true,
+                (Some(NumberWord::Hundred), NumberWord::Unit(_) | NumberWord::Tens(_) | NumberWord::Scale(_) | NumberWord::And) => true,
+                (Some(NumberWord::Scale(_)), NumberWord::Unit(_) | NumberWord::Tens(_) | NumberWord::And) => true,
+                (Some(NumberWord::And), NumberWord::Unit(_) | NumberWord::Tens(_)) => true,
+                _ => false,
+            };
+            if !fits {
+                break;
+            }
+            if matches!(word, NumberWord::Scale(scale) if scale >= last_scale) {
+                // The words since the last scale start a number of their own
+                (taken, end) = after_scale;
+                current = 0.0;
+                break;
+            }
+            match word {
+                NumberWord::Unit(n) | NumberWord::Tens(n) => current += n,
+                NumberWord::Hundred => current *= 100.0,
+                NumberWord::Scale(scale) => {
+                    total += current * scale;
+                    current = 0.0;
+                    last_scale = scale;
+                    after_scale = (next + 1, range.end);
+                }
+                NumberWord::And => {}
+            }
+            previous = Some(word);
+            next += 1;
+            // A trailing `and` isn't part of the number
+            if word != NumberWord::And {
+                taken = next;
+                end = range.end;
+            }
+        }
+
+        result.push_str(&input[copied..first.start]);
+        result.push_str(&(total + current).to_string());
+        copied = end;
+        idx = taken;
+    }
+    result.push_str(&input[copied..]);
+    result
+}
+
+// Whether the space at `idx` separates two digit groups of one number
+fn in_space_grouped_number(chars: &[char], idx: usize) -> bool {
+    let is_group = |start: usize| {
+        chars.get(start..start + 3).is_some_and(|group| group.iter().all(char::is_ascii_digit))
+            && !chars.get(start + 3).is_some_and(|c| c.is_alphanumeric())
+    };
+    if !is_group(idx + 1) {
+        return false;
+    }
+
+    // Walk back over the groups before this one to the leading digits, which must be 1 to 3
+    // digits that don't continue a longer number
+    let mut end = idx;
+    loop {
+        let digits = chars[..end].iter().rev().take_while(|c| c.is_ascii_digit()).count();
+        let start = end - digits;
+        if digits == 3 && start > 1 && chars[start - 1] == ' ' && chars[start - 2].is_ascii_digit() {
+            end = start - 1;
+            continue;
+        }
+        let continues_number = start > 0 && (matches!(chars[start - 1], '.' | ',') || chars[start - 1].is_alphanumeric());
+        return (1..=3).contains(&digits) && !continues_number;
+    }
+}
+
+// `1.234,56` → `1234.56`, and `nthroot(8; 3)` → `nthroot(8, 3)`. Only separators with a
+// digit on both sides are touched, so `, ` between arguments and `usd.` keep their meaning.
+// A `.` that isn't followed by a group of three digits, as in `1.5`, can't be a thousands
+// separator, so its character index is returned as the error instead of reading `15`.
+fn from_decimal_comma(input: &str) -> Result<String, usize> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut result = String::with_capacity(input.len());
+    let mut depth = 0usize;
+    // Whether the number being read already has its decimal point
+    let mut seen_decimal = false;
+
+    for (idx, &ch) in chars.iter().enumerate() {
+        let between_digits = idx > 0
+            && chars[idx - 1].is_ascii_digit()
+            && chars.get(idx + 1).is_some_and(|next| next.is_ascii_digit());
+        match ch {
+            ',' if between_digits && !seen_decimal => {
+                seen_decimal = true;
+                result.push('.');
+            }
+            '.' if between_digits && !seen_decimal => {
+                let group = chars[idx + 1..].iter().take_while(|c| c.is_ascii_digit()).count();
+                if group != 3 {
+                    return Err(idx);
+                }
+            }
+            ';' if depth > 0 => result.push(','),
+            _ => {
+                match ch {
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                if !ch.is_ascii_digit() {
+                    seen_decimal = false;
+                }
+                result.push(ch);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// A token as the parser reads it, along with how it was written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// What the parser sees: `10 k` is `10000`
+    pub text: String,
+    /// The input it came from, spelled as typed: `10 k`, `USD` or `usd`
+    pub raw: String,
+    /// Byte range of `raw` in the input
+    pub span: Range<usize>,
+}
+
+/// Split `input` into the tokens the parser reads, with multipliers such as `10 k`
+/// already folded into their number. Each token also keeps the text it was read from, for
+/// showing the user's input back as they wrote it.
+pub fn tokenize(input: &str) -> Vec<Token> {
+    let (tokens, spans, _) = tokenize_spanned(input);
+    let byte_at = |char_idx: usize| input.char_indices().nth(char_idx).map_or(input.len(), |(byte, _)| byte);
+    tokens
+        .into_iter()
+        .zip(spans)
+        .map(|(text, chars)| {
+            let written = &input[byte_at(chars.start)..byte_at(chars.end)];
+            // A word can pick up whitespace such as tabs, which isn't part of the token
+            let start = byte_at(chars.start) + (written.len() - written.trim_start().len());
+            let raw = written.trim().to_string();
+            Token { span: start..start + raw.len(), text, raw }
+        })
+        .collect()
+}
+
+/// Multiplier words (`k`, `m`, `cr`, ...) that `input` applies to a number, as written
+pub fn multiplier_words(input: &str) -> Vec<String> {
+    tokenize_spanned(input).2
+}
+
+// Tokens with the characters of `input` each one covers, plus the multiplier words that were
+// folded into the number before them
+fn tokenize_spanned(input: &str) -> (Vec<String>, Vec<Range<usize>>, Vec<String>) {
     let mut tokens = Vec::new();
+    let mut spans = Vec::new();
     let mut current = String::new();
+    let mut current_start = 0;
+    let mut chars = input.chars().enumerate().peekable();
+
+    // Ends the word being read at character `end`
+    let flush = |current: &mut String, start: usize, end: usize, tokens: &mut Vec<String>, spans: &mut Vec<Range<usize>>| {
+        if !current.is_empty() {
+            tokens.push(current.trim().to_string());
+            spans.push(start..end);
+            current.clear();
+        }
+    };
 
-    for ch in input.chars() {
+    while let Some((pos, ch)) = chars.next() {
         match ch {
-            '+' | '-' | '*' | '/' | '%' | '^' | '(' | ')' => {
-                if !current.is_empty() {
-                    tokens.push(current.trim().to_string());
-                    current.clear();
-                }
+            // The sign of an exponent stays with its number: `1e-3`, not `1e - 3`
+            '+' | '-' if is_open_exponent(&current) => current.push(ch),
+            '+' | '-' | '*' | '/' | '%' | '^' | '(' | ')' | ',' | '$' | '€' | '₹' => {
+                flush(&mut current, current_start, pos, &mut tokens, &mut spans);
                 tokens.push(ch.to_string());
+                spans.push(pos..pos + 1);
             }
-            ' ' => {
-                if !current.is_empty() {
-                    tokens.push(current.trim().to_string());
-                    current.clear();
+            '<' | '>' | '=' | '!' => {
+                flush(&mut current, current_start, pos, &mut tokens, &mut spans);
+                // Two-character operators: <=, >=, ==, !=
+                if chars.peek().map(|&(_, next)| next) == Some('=') {
+                    chars.next();
+                    tokens.push(format!("{}=", ch));
+                    spans.push(pos..pos + 2);
+                } else {
+                    tokens.push(ch.to_string());
+                    spans.push(pos..pos + 1);
                 }
             }
-            _ => current.push(ch),
+            ' ' => flush(&mut current, current_start, pos, &mut tokens, &mut spans),
+            _ => {
+                if current.is_empty() {
+                    current_start = pos;
+                }
+                current.push(ch);
+            }
         }
     }
 
-    if !current.is_empty() {
-        tokens.push(current.trim().to_string());
-    }
+    flush(&mut current, current_start, input.chars().count(), &mut tokens, &mut spans);
+
+    // Split glued suffixes so `10k` and `100USD` read like `10 k` and `100 USD`
+    let (tokens, spans): (Vec<String>, Vec<Range<usize>>) = tokens
+        .into_iter()
+        .zip(spans)
+        .flat_map(|(token, span)| match split_number_suffix(&token) {
+            // The number part is ASCII, so its length in bytes is its length in characters
+            Some((number, suffix)) => {
+                let split = span.start + number.len();
+                vec![(number.to_string(), span.start..split), (suffix.to_string(), split..span.end)]
+            }
+            None => vec![(token, span)],
+        })
+        .unzip();
 
     // Post-process: combine number + text_multiplier into a single token
     let mut processed = Vec::new();
+    let mut processed_spans = Vec::new();
+    let mut multipliers = Vec::new();
     let mut i = 0;
 
     while i < tokens.len() {
@@ -234,6 +1105,8 @@ fn tokenize(input: &str) -> Vec<String> {
                     // Combine number and multiplier
                     let combined_value = num * multiplier;
                     processed.push(combined_value.to_string());
+                    processed_spans.push(spans[i].start..spans[i + 1].end);
+                    multipliers.push(tokens[i + 1].clone());
                     i += 2; // Skip both tokens
                     continue;
                 }
@@ -241,10 +1114,75 @@ fn tokenize(input: &str) -> Vec<String> {
         }
 
         processed.push(tokens[i].clone());
+        processed_spans.push(spans[i].clone());
         i += 1;
     }
 
-    processed
+    (processed, processed_spans, multipliers)
+}
+
+// For each character of `rewritten` (the input after strip_grouping_commas or
+// from_decimal_comma), the index of the character of `original` it came from. Both only drop
+// separators or swap one for another, so walking the two side by side lines them up.
+fn char_origins(original: &str, rewritten: &str) -> Vec<usize> {
+    let mut source = original.chars().enumerate();
+    let mut origins = Vec::with_capacity(rewritten.len());
+    for ch in rewritten.chars() {
+        let swapped = |original_ch: char| matches!((original_ch, ch), (',', '.') | (';', ','));
+        if let Some((idx, _)) = source.by_ref().find(|&(_, original_ch)| original_ch == ch || swapped(original_ch)) {
+            origins.push(idx);
+        }
+    }
+    origins
+}
+
+// The bytes of `original` that characters `chars` of its rewritten form came from
+fn original_span(original: &str, origins: &[usize], chars: Range<usize>) -> Range<usize> {
+    let byte_at = |char_idx: usize| original.char_indices().nth(char_idx).map_or(original.len(), |(byte, _)| byte);
+    let (Some(&first), Some(&last)) = (origins.get(chars.start), origins.get(chars.end - 1)) else {
+        return original.len()..original.len();
+    };
+    byte_at(first)..byte_at(last + 1)
+}
+
+// `1e` or `2.5E`: a number whose exponent hasn't been written yet
+fn is_open_exponent(token: &str) -> bool {
+    match token.strip_suffix(['e', 'E']) {
+        Some(mantissa) => mantissa.starts_with(|c: char| c.is_ascii_digit() || c == '.') && mantissa.parse::<f64>().is_ok(),
+        None => false,
+    }
+}
+
+fn comparison_operator(token: &str) -> Option<Operator> {
+    match token {
+        "<" => Some(Operator::Less),
+        "<=" => Some(Operator::LessEqual),
+        ">" => Some(Operator::Greater),
+        ">=" => Some(Operator::GreaterEqual),
+        "==" => Some(Operator::Equal),
+        "!=" => Some(Operator::NotEqual),
+        _ => None,
+    }
+}
+
+// `2.5m` → ("2.5", "m"). Only known multipliers and currencies are split off, so other
+// glued text still fails to parse instead of being silently misread.
+fn split_number_suffix(token: &str) -> Option<(&str, &str)> {
+    if token.parse::<f64>().is_ok() {
+        return None;
+    }
+
+    let idx = token.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, suffix) = token.split_at(idx);
+    if number.parse::<f64>().is_err() {
+        return None;
+    }
+
+    if text_to_multiplier(suffix) != 1.0 || is_currency(suffix) {
+        Some((number, suffix))
+    } else {
+        None
+    }
 }
 
 fn text_to_multiplier(text: &str) -> f64 {
@@ -262,19 +1200,56 @@ fn text_to_multiplier(text: &str) -> f64 {
     }
 }
 
+// Operators that need something after them
+const TRAILING_OPERATORS: &[&str] = &[
+    "+", "-", "*", "/", "%", "^", "mod", "per", "<", "<=", ">", ">=", "==", "!=", "=", "to", "of",
+];
+
+/// Keywords that can't be used as variable names
+pub const RESERVED_WORDS: &[&str] = &["mod", "per", "to", "of"];
+
+// What a reserved word is for, to explain why it can't be a name
+fn reserved_use(word: &str) -> &'static str {
+    match word.to_lowercase().as_str() {
+        "to" => "it converts currencies, as in `100 USD to INR`",
+        "of" => "it takes a percentage, as in `15% of 200`",
+        "mod" => "it is the remainder operator, as in `7 mod 3`",
+        "per" => "it divides, as in `120 km per 2 h`",
+        _ => "it is a keyword",
+    }
+}
+
+pub fn is_reserved(word: &str) -> bool {
+    RESERVED_WORDS.iter().any(|reserved| reserved.eq_ignore_ascii_case(word))
+}
+
+/// Currency codes the parser recognizes (symbols like $ map onto these)
+pub const CURRENCY_CODES: &[&str] = &["USD", "EUR", "INR"];
+
+// Symbols accepted in place of a code
+const CURRENCY_SYMBOLS: &[&str] = &["$", "€", "₹"];
+
+// Currency tokens may carry a trailing period, as in `100 usd to inr.`
+fn strip_trailing_period(token: &str) -> &str {
+    token.strip_suffix('.').unwrap_or(token)
+}
+
 fn is_currency(token: &str) -> bool {
-    matches!(token.to_uppercase().as_str(),
-        "USD" | "EUR" | "INR" | "$" | "€" | "₹"
-    )
+    let upper = strip_trailing_period(token).to_uppercase();
+    CURRENCY_CODES.contains(&upper.as_str()) || CURRENCY_SYMBOLS.contains(&upper.as_str())
 }
 
-fn normalize_currency(symbol: &str) -> String {
-    match symbol.to_uppercase().as_str() {
-        "$" | "USD" => "USD".to_string(),
-        "€" | "EUR" => "EUR".to_string(),
-        "₹" | "INR" => "INR".to_string(),
-        _ => symbol.to_uppercase(),
-    }
+// Three capital letters, as ISO currency codes are written
+fn looks_like_currency_code(token: &str) -> bool {
+    token.len() == 3 && token.chars().all(|c| c.is_ascii_uppercase())
+}
+
+// A word that can label a number: not an operator word like `to` or `mod`
+fn is_unit(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_alphabetic())
+        && token.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !TRAILING_OPERATORS.iter().any(|op| op.eq_ignore_ascii_case(token))
+        && !is_reserved(token)
 }
 
 #[cfg(test)]
@@ -291,6 +1266,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_parse_decimal() {
         let parser = Parser::new();
         match parser.parse("3.14") {
@@ -376,6 +1352,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_subtraction_and_division_chains_are_left_associative() {
+        let parser = Parser::new();
+        // 10 - 3 - 2 should parse as (10 - 3) - 2
+        for (input, op) in [("10 - 3 - 2", "Subtract"), ("100 / 5 / 2", "Divide"), ("20 - 5 + 3", "Add"), ("7 mod 4 mod 2", "Modulo")] {
+            match parser.parse(input) {
+                Ok(Expression::BinaryOp { op: outer, left, right }) => {
+                    assert_eq!(format!("{:?}", outer), op, "{}", input);
+                    assert!(matches!(*left, Expression::BinaryOp { .. }), "Expected the first two operands grouped in {}", input);
+                    assert!(matches!(*right, Expression::Number(_)), "{}", input);
+                }
+                other => panic!("Expected BinaryOp for {:?}, got {:?}", input, other),
+            }
+        }
+        // Powers are the exception: 2 ^ 3 ^ 2 is 2 ^ (3 ^ 2)
+        assert!(matches!(parser.parse("2 ^ 3 ^ 2").unwrap(), Expression::BinaryOp { op: Operator::Power, ref right, .. }
+            if matches!(**right, Expression::BinaryOp { op: Operator::Power, .. })));
+    }
+
     #[test]
     fn test_parentheses_override_precedence() {
         let parser = Parser::new();
@@ -472,6 +1467,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_unit_annotation() {
+        let parser = Parser::new();
+        match parser.parse("50 apples") {
+            Ok(Expression::UnitAnnotation { value, unit }) => {
+                assert!(matches!(*value, Expression::Number(n) if n == 50.0));
+                assert_eq!(unit, "apples");
+            }
+            _ => panic!("Expected UnitAnnotation"),
+        }
+        // Operator words and function calls aren't units, and nor are currency codes
+        assert!(matches!(parser.parse("7 mod 3"), Ok(Expression::BinaryOp { op: Operator::Modulo, .. })));
+        assert_eq!(parser.parse("100 GBP to INR").unwrap_err(), "Unsupported currency: GBP");
+        assert!(matches!(parser.parse("100 gbp"), Ok(Expression::UnitAnnotation { .. })));
+        assert!(parser.parse("100 USD to INR").is_ok());
+        assert!(parser.parse("2 sqrt(4)").is_err());
+    }
+
     #[test]
     fn test_simple_currency_conversion() {
         let parser = Parser::new();
@@ -577,13 +1590,13 @@ mod tests {
     }
 
     #[test]
-    fn test_normalize_currency() {
-        assert_eq!(normalize_currency("$"), "USD");
-        assert_eq!(normalize_currency("USD"), "USD");
-        assert_eq!(normalize_currency("€"), "EUR");
-        assert_eq!(normalize_currency("EUR"), "EUR");
-        assert_eq!(normalize_currency("₹"), "INR");
-        assert_eq!(normalize_currency("INR"), "INR");
+    fn test_currency_symbols_normalize() {
+        assert_eq!(Currency::new("$").unwrap(), "USD");
+        assert_eq!(Currency::new("USD").unwrap(), "USD");
+        assert_eq!(Currency::new("€").unwrap(), "EUR");
+        assert_eq!(Currency::new("EUR").unwrap(), "EUR");
+        assert_eq!(Currency::new("₹").unwrap(), "INR");
+        assert_eq!(Currency::new("INR").unwrap(), "INR");
     }
 
     #[test]
@@ -611,4 +1624,683 @@ mod tests {
         assert_eq!(text_to_multiplier("thousand"), 1_000.0);
         assert_eq!(text_to_multiplier("k"), 1_000.0);
     }
+
+    #[test]
+    fn test_parse_comparison() {
+        let parser = Parser::new();
+        match parser.parse("2 + 3 >= 5") {
+            Ok(Expression::Comparison { first, rest }) => {
+                assert!(matches!(*first, Expression::BinaryOp { op: Operator::Add, .. }));
+                assert_eq!(rest.len(), 1);
+                assert!(matches!(rest[0].0, Operator::GreaterEqual));
+            }
+            _ => panic!("Expected Comparison"),
+        }
+    }
+
+    #[test]
+    fn test_parse_chained_comparison() {
+        let parser = Parser::new();
+        // 1 < x < 10 is one chain, not (1 < x) < 10
+        match parser.parse("1 < x < 10") {
+            Ok(Expression::Comparison { first, rest }) => {
+                assert!(matches!(*first, Expression::Number(n) if n == 1.0));
+                assert_eq!(rest.len(), 2);
+                assert!(matches!(rest[0], (Operator::Less, Expression::Variable(ref name)) if name == "x"));
+                assert!(matches!(rest[1], (Operator::Less, Expression::Number(n)) if n == 10.0));
+            }
+            _ => panic!("Expected chained Comparison"),
+        }
+    }
+
+    #[test]
+    fn test_equality_is_not_assignment() {
+        let parser = Parser::new();
+        assert!(matches!(parser.parse("x == 5"), Ok(Expression::Comparison { .. })));
+        assert!(matches!(parser.parse("x != 5"), Ok(Expression::Comparison { .. })));
+    }
+
+    #[test]
+    fn test_parse_function_call() {
+        let parser = Parser::new();
+        match parser.parse("sqrt(16) + 1") {
+            Ok(Expression::BinaryOp { op: Operator::Add, left, .. }) => match *left {
+                Expression::FunctionCall { name, args } => {
+                    assert_eq!(name, "sqrt");
+                    assert_eq!(args.len(), 1);
+                }
+                _ => panic!("Expected FunctionCall on the left"),
+            },
+            _ => panic!("Expected Add"),
+        }
+    }
+
+    #[test]
+    fn test_parse_function_call_multiple_args() {
+        let parser = Parser::new();
+        match parser.parse("f(1, 2 + 3, (4))") {
+            Ok(Expression::FunctionCall { args, .. }) => assert_eq!(args.len(), 3),
+            _ => panic!("Expected FunctionCall"),
+        }
+        assert!(parser.parse("f(1, 2").is_err());
+    }
+
+    #[test]
+    fn test_error_trailing_tokens() {
+        let parser = Parser::new();
+        // `1,000` is a grouped number, but `1,00` isn't
+        assert!(parser.parse("1,00").is_err());
+        assert!(parser.parse("2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_percent_of() {
+        let parser = Parser::new();
+        match parser.parse("10% of 500 USD") {
+            Ok(Expression::PercentOf { percent, base }) => {
+                assert!(matches!(*percent, Expression::Number(n) if n == 10.0));
+                assert!(matches!(*base, Expression::CurrencyAnnotation { .. }));
+            }
+            _ => panic!("Expected PercentOf"),
+        }
+    }
+
+    #[test]
+    fn test_parse_percent_of_precedence() {
+        let parser = Parser::new();
+        match parser.parse("2 * 10% of 50") {
+            Ok(Expression::BinaryOp { op: Operator::Multiply, right, .. }) => {
+                assert!(matches!(*right, Expression::PercentOf { .. }));
+            }
+            _ => panic!("Expected Multiply with PercentOf on right"),
+        }
+        assert!(parser.parse("10% of").is_err());
+    }
+
+    #[test]
+    fn test_parse_factorial() {
+        let parser = Parser::new();
+        match parser.parse("25! / 24!") {
+            Ok(Expression::BinaryOp { op: Operator::Divide, left, right }) => {
+                assert!(matches!(*left, Expression::Factorial(_)));
+                assert!(matches!(*right, Expression::Factorial(_)));
+            }
+            _ => panic!("Expected division of factorials"),
+        }
+        // `!=` is still a comparison
+        assert!(matches!(parser.parse("5 != 3"), Ok(Expression::Comparison { .. })));
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        let parser = Parser::new();
+        let input = format!("{}1{}", "(".repeat(50_000), ")".repeat(50_000));
+        assert_eq!(parser.parse(&input).unwrap_err(), "Expression too deeply nested");
+
+        let percents = "1% of ".repeat(50_000) + "100";
+        assert_eq!(parser.parse(&percents).unwrap_err(), "Expression too deeply nested");
+    }
+
+    #[test]
+    fn test_nesting_limit_is_configurable() {
+        let mut parser = Parser::new();
+        parser.set_max_depth(3);
+        assert!(parser.parse("(((1)))").is_ok());
+        assert!(parser.parse("((((1))))").is_err());
+        assert!(parser.parse("sqrt(sqrt(sqrt(sqrt(16))))").is_err());
+    }
+
+    #[test]
+    fn test_parse_many() {
+        let parser = Parser::new();
+        let statements = parser.parse_many("a = 5; b = 10; a + b").unwrap();
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(&statements[0], Expression::Assignment { var, .. } if var == "a"));
+        assert!(matches!(&statements[2], Expression::BinaryOp { op: Operator::Add, .. }));
+
+        // A trailing `;` doesn't add an empty statement
+        assert_eq!(parser.parse_many("1 + 1;").unwrap().len(), 1);
+        assert!(parser.parse_many(" ; ").is_err());
+        assert!(parser.parse_many("1; 2 +").is_err());
+    }
+
+    #[test]
+    fn test_split_statements_ignores_nested_semicolons() {
+        assert_eq!(split_statements("a = 1; (b; c); d"), vec!["a = 1", " (b; c)", " d"]);
+    }
+
+    #[test]
+    fn test_currency_case_and_trailing_punctuation() {
+        let parser = Parser::new();
+        for input in ["100 usd to inr", "100 usd To Inr.", "100 Usd TO INR,", "100 usd. to inr"] {
+            match parser.parse(input) {
+                Ok(Expression::CurrencyConversion { source, target_currency }) => {
+                    assert_eq!(target_currency, "INR", "input: {}", input);
+                    assert!(
+                        matches!(*source, Expression::CurrencyAnnotation { ref currency, .. } if currency == "USD"),
+                        "input: {}",
+                        input
+                    );
+                }
+                other => panic!("Expected conversion for {:?}, got {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_glued_multiplier_suffixes() {
+        let tokenize = |input: &str| tokenize(input).into_iter().map(|token| token.text).collect::<Vec<_>>();
+        assert_eq!(tokenize("10k"), vec!["10000"]);
+        assert_eq!(tokenize("2.5m + 3cr"), vec!["2500000", "+", "30000000"]);
+        assert_eq!(tokenize("1.5Lakh"), vec!["150000"]);
+        // Unknown suffixes stay glued and fail to parse
+        assert_eq!(tokenize("10x"), vec!["10x"]);
+    }
+
+    #[test]
+    fn test_tokenize_keeps_spelling() {
+        let raw = |input: &str| tokenize(input).into_iter().map(|token| (token.text, token.raw)).collect::<Vec<_>>();
+        let pair = |text: &str, raw: &str| (text.to_string(), raw.to_string());
+        assert_eq!(
+            raw("2.5 Lakh uSd TO Inr"),
+            vec![pair("250000", "2.5 Lakh"), pair("uSd", "uSd"), pair("TO", "TO"), pair("Inr", "Inr")]
+        );
+        assert_eq!(raw("€10k >= 5"), vec![pair("€", "€"), pair("10000", "10k"), pair(">=", ">="), pair("5", "5")]);
+        assert_eq!(raw("Sqrt(₹ 4)"), vec![pair("Sqrt", "Sqrt"), pair("(", "("), pair("₹", "₹"), pair("4", "4"), pair(")", ")")]);
+
+        // Spans are byte ranges of the input, so multi-byte symbols count their full width
+        let input = "₹ 100 + 2 cr";
+        for token in tokenize(input) {
+            assert_eq!(&input[token.span.clone()], token.raw);
+        }
+        assert_eq!(tokenize(input)[1].span, 4..7);
+    }
+
+    #[test]
+    fn test_glued_currency_is_not_a_multiplier() {
+        let parser = Parser::new();
+        match parser.parse("100USD").unwrap() {
+            Expression::CurrencyAnnotation { currency, .. } => assert_eq!(currency, "USD"),
+            other => panic!("Expected currency annotation, got {:?}", other),
+        }
+        match parser.parse("5k usd to inr").unwrap() {
+            Expression::CurrencyConversion { source, .. } => match *source {
+                Expression::CurrencyAnnotation { value, currency } => {
+                    assert!(matches!(*value, Expression::Number(n) if n == 5000.0));
+                    assert_eq!(currency, "USD");
+                }
+                other => panic!("Expected currency annotation, got {:?}", other),
+            },
+            other => panic!("Expected conversion, got {:?}", other),
+        }
+        assert!(matches!(parser.parse("100inr").unwrap(), Expression::CurrencyAnnotation { .. }));
+    }
+
+    #[test]
+    fn test_power_and_unary_minus() {
+        let parser = Parser::new();
+        match parser.parse("2 ^ -1").unwrap() {
+            Expression::BinaryOp { op: Operator::Power, left, right } => {
+                assert!(matches!(*left, Expression::Number(n) if n == 2.0));
+                assert!(matches!(*right, Expression::Negate(_)));
+            }
+            other => panic!("Expected power, got {:?}", other),
+        }
+        // Right-associative
+        match parser.parse("2 ^ 3 ^ 2").unwrap() {
+            Expression::BinaryOp { op: Operator::Power, right, .. } => {
+                assert!(matches!(*right, Expression::BinaryOp { op: Operator::Power, .. }));
+            }
+            other => panic!("Expected power, got {:?}", other),
+        }
+        // `-2 ^ 2` negates the power
+        assert!(matches!(parser.parse("-2 ^ 2").unwrap(), Expression::Negate(_)));
+        assert!(matches!(parser.parse("3 * -x").unwrap(), Expression::BinaryOp { op: Operator::Multiply, .. }));
+
+        let deep = "-".repeat(50_000) + "1";
+        assert_eq!(parser.parse(&deep).unwrap_err(), "Expression too deeply nested");
+    }
+
+    #[test]
+    fn test_modulo_and_mod_alias() {
+        let parser = Parser::new();
+        for input in ["17 % 5", "17 mod 5", "17 MOD 5"] {
+            assert!(
+                matches!(parser.parse(input).unwrap(), Expression::BinaryOp { op: Operator::Modulo, .. }),
+                "input: {}",
+                input
+            );
+        }
+
+        // Same precedence as * and /, left to right: (2 * 17) mod 5
+        match parser.parse("2 * 17 mod 5").unwrap() {
+            Expression::BinaryOp { op: Operator::Modulo, left, .. } => {
+                assert!(matches!(*left, Expression::BinaryOp { op: Operator::Multiply, .. }));
+            }
+            other => panic!("Expected modulo, got {:?}", other),
+        }
+        // `% of` still means a percentage
+        assert!(matches!(parser.parse("10% of 50").unwrap(), Expression::PercentOf { .. }));
+    }
+
+    #[test]
+    fn test_mod_is_reserved() {
+        let parser = Parser::new();
+        assert_eq!(
+            parser.parse("mod = 5").unwrap_err(),
+            "Cannot assign to reserved word: mod (it is the remainder operator, as in `7 mod 3`)"
+        );
+        assert_eq!(parser.parse("mod + 1").unwrap_err(), "Unexpected keyword: mod");
+    }
+
+    #[test]
+    fn test_per_divides() {
+        let parser = Parser::new();
+        assert!(matches!(parser.parse("100 per 4").unwrap(), Expression::BinaryOp { op: Operator::Divide, .. }));
+        assert!(matches!(parser.parse("120 km PER 2 h").unwrap(), Expression::BinaryOp { op: Operator::Divide, ref left, ref right }
+            if matches!(**left, Expression::UnitAnnotation { ref unit, .. } if unit == "km")
+                && matches!(**right, Expression::UnitAnnotation { ref unit, .. } if unit == "h")));
+        assert_eq!(
+            parser.parse("per = 5").unwrap_err(),
+            "Cannot assign to reserved word: per (it divides, as in `120 km per 2 h`)"
+        );
+        assert!(parser.is_incomplete("100 per"));
+    }
+
+    #[test]
+    fn test_to_and_of_are_reserved() {
+        let parser = Parser::new();
+        assert_eq!(
+            parser.parse("to = 5").unwrap_err(),
+            "Cannot assign to reserved word: to (it converts currencies, as in `100 USD to INR`)"
+        );
+        assert_eq!(
+            parser.parse("OF = 5").unwrap_err(),
+            "Cannot assign to reserved word: OF (it takes a percentage, as in `15% of 200`)"
+        );
+        assert_eq!(parser.parse("to * 2").unwrap_err(), "Unexpected keyword: to");
+        assert_eq!(parser.parse("5 + of").unwrap_err(), "Unexpected keyword: of");
+
+        // Only whole words: names that merely start with them are fine
+        assert!(matches!(parser.parse("total = 5").unwrap(), Expression::Assignment { .. }));
+        assert!(matches!(parser.parse("offset = 5").unwrap(), Expression::Assignment { .. }));
+        assert!(matches!(parser.parse("100 USD to INR").unwrap(), Expression::CurrencyConversion { .. }));
+    }
+
+    #[test]
+    fn test_multiplier_words() {
+        assert_eq!(multiplier_words("10 k + 2m - 3 Lakh"), vec!["k", "m", "Lakh"]);
+        assert!(multiplier_words("k * 10").is_empty());
+    }
+
+    #[test]
+    fn test_parsers_share_the_compiled_regex() {
+        let first = Parser::new();
+        for _ in 0..10_000 {
+            let parser = Parser::new();
+            assert!(std::ptr::eq(parser.assignment_regex, first.assignment_regex));
+        }
+        assert!(matches!(first.parse("x = 1").unwrap(), Expression::Assignment { .. }));
+    }
+
+    // Inputs that have tripped the tokenizer or parser up before
+    const SEED_CORPUS: &[&str] = &[
+        "",
+        " ",
+        "\t",
+        "-",
+        "--5",
+        "-(-(-1))",
+        "1e",
+        "1e-",
+        "1e308 * 10",
+        ".",
+        "..5",
+        "5.",
+        "1..2",
+        "10k",
+        "10kk",
+        "2.5mUSD",
+        "100USD to",
+        "to INR",
+        "5 to to INR",
+        "()",
+        "(((",
+        ")))",
+        "sqrt(",
+        "nthroot(,)",
+        "x =",
+        "= 5",
+        "==",
+        "!",
+        "5!!",
+        "10% of",
+        "% of 5",
+        "mod mod",
+        "1 < < 2",
+        "€₹$",
+        "१२३",
+        "5 \u{200b} 3",
+        ";;",
+        "a = 1; ;",
+    ];
+
+    #[test]
+    fn test_seed_corpus_does_not_panic() {
+        let parser = Parser::new();
+        for input in SEED_CORPUS {
+            let _ = parser.parse(input);
+            let _ = parser.parse_many(input);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parse_never_panics(input in "\\PC{0,40}") {
+            let parser = Parser::new();
+            let _ = parser.parse(&input);
+            let _ = parser.parse_many(&input);
+        }
+
+        // Arbitrary strings rarely get past the tokenizer, so also mix the tokens it knows
+        #[test]
+        fn parse_never_panics_on_token_soup(
+            tokens in proptest::collection::vec(
+                proptest::sample::select(vec![
+                    "1", "2.5", "-", "+", "*", "/", "^", "%", "!", "(", ")", ",", "=", "<", "==",
+                    "to", "of", "mod", "k", "cr", "USD", "€", "x", "sqrt", "1e5", ".", ";",
+                ]),
+                0..16,
+            ),
+            glue in proptest::bool::ANY,
+        ) {
+            let input = tokens.join(if glue { "" } else { " " });
+            let parser = Parser::new();
+            let _ = parser.parse(&input);
+            let _ = parser.parse_many(&input);
+        }
+    }
+
+    #[test]
+    fn test_scientific_notation_vs_e() {
+        let parser = Parser::new();
+        assert!(matches!(parser.parse("1e6").unwrap(), Expression::Number(n) if n == 1e6));
+        assert!(matches!(parser.parse("1e-3").unwrap(), Expression::Number(n) if n == 1e-3));
+        assert!(matches!(parser.parse("2.5E+2").unwrap(), Expression::Number(n) if n == 250.0));
+        assert!(matches!(parser.parse("e").unwrap(), Expression::Variable(ref name) if name == "e"));
+        assert!(matches!(parser.parse("e - 1").unwrap(), Expression::BinaryOp { op: Operator::Subtract, .. }));
+        assert_eq!(parser.parse("2e").unwrap_err(), "Missing exponent in 2e");
+        assert_eq!(parser.parse("2e+").unwrap_err(), "Missing exponent in 2e+");
+    }
+
+    #[test]
+    fn test_validate() {
+        let parser = Parser::new();
+        for input in ["", "  ", "1 + 2", "x = 5", "100 USD to INR", "a = 1; a * 2", "sqrt(16)"] {
+            assert_eq!(parser.validate(input), Ok(()), "{:?}", input);
+        }
+        assert_eq!(parser.validate("5 +"), parser.parse("5 +").map(|_| ()));
+        assert!(parser.validate("(1 + 2").is_err());
+        assert!(parser.validate("1 + 2; 3 *").is_err());
+        assert!(parser.validate("mod = 3").is_err());
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let parser = Parser::new();
+        let span = |input: &str| parser.check(input).unwrap_err().span;
+
+        assert_eq!(span("5 * * 2"), 4..5);
+        assert_eq!(span("3 3"), 2..3);
+        assert_eq!(span("2 + mod"), 4..7);
+        assert_eq!(span("sqrt(4 4)"), 7..8);
+        // Ran out of input: an empty span at the end
+        assert_eq!(span("(1 + 2"), 6..6);
+        assert_eq!(span("100 USD to"), 10..10);
+        // Through grouping commas, multipliers, glued suffixes and non-ASCII symbols
+        assert_eq!(span("1,000 + 2)"), 9..10);
+        assert_eq!(span("10 k 3"), 5..6);
+        assert_eq!(span("2.5cr 7"), 6..7);
+        assert_eq!(span("₹ 5 ) "), 6..7);
+        assert_eq!(span("100 USD to INR, XYZW"), 16..20);
+        // Offsets carry through assignments, statements and surrounding space
+        assert_eq!(span("  x = 2 +* 3"), 9..10);
+        assert_eq!(span("a = 1; 2 2"), 9..10);
+        assert_eq!(span("mod = 3"), 0..3);
+
+        let mut parser = Parser::new();
+        parser.set_decimal_comma(true);
+        assert_eq!(parser.check("1.234,5 )").unwrap_err().span, 8..9);
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        let parser = Parser::new();
+        for input in ["100 +", "100 + ", "2 * (3 + 4) -", "x =", "100 USD to", "5 ==", "a = 1; a *", "17 MOD"] {
+            assert!(parser.parse_many(input).is_err(), "{:?}", input);
+            assert!(parser.is_incomplete(input), "{:?}", input);
+        }
+        for input in ["100 + +", "+", "100", "(1 + 2", "", "100 + 2)"] {
+            assert!(!parser.is_incomplete(input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_invalid_target_currency() {
+        let parser = Parser::new();
+        assert_eq!(parser.parse("100 USD to 42").unwrap_err(), "Invalid currency: 42");
+        assert!(matches!(parser.parse("100 usd to pkr").unwrap(), Expression::CurrencyConversion { target_currency, .. } if target_currency == "PKR"));
+    }
+
+    #[test]
+    fn test_trailing_equals() {
+        let parser = Parser::new();
+        assert!(matches!(parser.parse("2 + 3 =").unwrap(), Expression::BinaryOp { op: Operator::Add, .. }));
+        assert!(matches!(parser.parse("100 USD to INR =").unwrap(), Expression::CurrencyConversion { .. }));
+        assert!(matches!(parser.parse("x = 2 * 3 =").unwrap(), Expression::Assignment { .. }));
+        assert!(matches!(parser.parse("x = 5").unwrap(), Expression::Assignment { .. }));
+        // Still incomplete rather than an echo of `x`
+        assert!(parser.parse("x =").is_err());
+        assert!(parser.is_incomplete("x ="));
+        assert!(parser.parse("5 ==").is_err());
+        assert!(parser.parse("=").is_err());
+    }
+
+    #[test]
+    fn test_leading_equals() {
+        let parser = Parser::new();
+        assert!(matches!(parser.parse("=2+3").unwrap(), Expression::BinaryOp { op: Operator::Add, .. }));
+        assert!(matches!(parser.parse("  = 100 USD to INR").unwrap(), Expression::CurrencyConversion { .. }));
+        // An identifier before `=` still makes an assignment
+        assert!(matches!(parser.parse("x=5").unwrap(), Expression::Assignment { ref var, .. } if var == "x"));
+
+        // A bare marker is waiting for its formula
+        assert_eq!(parser.parse("=").unwrap_err(), "Expected expression after =");
+        assert!(parser.is_incomplete("="));
+        assert!(parser.is_incomplete("=2 +"));
+        assert!(parser.parse("==3").is_err());
+        // Error spans still point into the original line
+        assert_eq!(parser.check("=2 + * 3").unwrap_err().span, 5..6);
+    }
+
+    #[test]
+    fn test_decimal_comma_input() {
+        let mut parser = Parser::new();
+        parser.set_decimal_comma(true);
+        assert!(matches!(parser.parse("2,75").unwrap(), Expression::Number(n) if n == 2.75));
+        assert!(matches!(parser.parse("1.234,56").unwrap(), Expression::Number(n) if n == 1234.56));
+        assert!(matches!(parser.parse("1.234.567").unwrap(), Expression::Number(n) if n == 1234567.0));
+        assert!(matches!(parser.parse("x = 2,5").unwrap(), Expression::Assignment { .. }));
+        assert!(matches!(parser.parse("2,5 k").unwrap(), Expression::Number(n) if n == 2500.0));
+        assert!(matches!(parser.parse("nthroot(8; 3)").unwrap(), Expression::FunctionCall { ref args, .. } if args.len() == 2));
+        assert!(matches!(parser.parse("nthroot(8, 3)").unwrap(), Expression::FunctionCall { ref args, .. } if args.len() == 2));
+        assert!(parser.parse("1,5,3").is_err());
+        // A `.` that doesn't fit a group of three is rejected rather than read as `15`
+        assert_eq!(parser.check("1.5").unwrap_err().span, 1..2);
+        assert!(parser.parse("1.2345").is_err());
+        assert!(parser.parse("2 * 12.34").is_err());
+        assert_eq!(parser.parse_many("a = 1,5; a * 2").unwrap().len(), 2);
+
+        // Off by default: `,` is never a decimal point
+        assert!(Parser::new().parse("3,14").is_err());
+        assert_eq!(from_decimal_comma("100 usd."), Ok("100 usd.".to_string()));
+    }
+
+    #[test]
+    fn test_word_numbers() {
+        let mut parser = Parser::new();
+        parser.set_word_numbers(true);
+        let number = |input: &str| match parser.parse(input) {
+            Ok(Expression::Number(n)) => n,
+            other => panic!("Expected a number for {:?}, got {:?}", input, other),
+        };
+        assert_eq!(number("two thousand"), 2000.0);
+        assert_eq!(number("five lakh"), 500_000.0);
+        assert_eq!(number("twenty-five"), 25.0);
+        assert_eq!(number("Two Crore Fifty Lakh"), 25_000_000.0);
+        assert_eq!(number("one hundred and five"), 105.0);
+        assert_eq!(number("three million four hundred thousand"), 3_400_000.0);
+        assert!(matches!(parser.parse("two thousand + one").unwrap(), Expression::BinaryOp { .. }));
+        assert!(matches!(parser.parse("five lakh INR").unwrap(), Expression::CurrencyAnnotation { ref value, .. }
+            if matches!(**value, Expression::Number(n) if n == 500_000.0)));
+
+        assert_eq!(from_number_words("two three"), "2 3");
+        assert_eq!(from_number_words("two thousand three thousand"), "2000 3000");
+        assert_eq!(from_number_words("ten and"), "10 and");
+        assert_eq!(from_number_words("someone + one_two + k"), "someone + one_two + k");
+        assert_eq!(from_number_words("thousand"), "thousand");
+
+        // Off by default: number words are variables
+        assert!(matches!(Parser::new().parse("two").unwrap(), Expression::Variable(_)));
+    }
+
+    #[test]
+    fn test_space_grouped_input() {
+        let mut parser = Parser::new();
+        parser.set_space_grouping(true);
+        let number = |input: &str| match parser.parse(input) {
+            Ok(Expression::Number(n)) => n,
+            other => panic!("Expected a number for {:?}, got {:?}", input, other),
+        };
+        assert_eq!(number("1 234 567"), 1234567.0);
+        assert_eq!(number("12 345.5"), 12345.5);
+        assert_eq!(number("2 000 k"), 2_000_000.0);
+        assert!(matches!(parser.parse("1 234 + 5 678").unwrap(), Expression::BinaryOp { .. }));
+        assert!(matches!(parser.parse("1 500 USD to INR").unwrap(), Expression::CurrencyConversion { ref source, .. }
+            if matches!(**source, Expression::CurrencyAnnotation { ref value, .. } if matches!(**value, Expression::Number(n) if n == 1500.0))));
+
+        // Multipliers, currencies and units after a number are not groups
+        assert_eq!(number("2 cr"), 20_000_000.0);
+        assert!(matches!(parser.parse("100 USD").unwrap(), Expression::CurrencyAnnotation { .. }));
+        assert!(matches!(parser.parse("500 apples").unwrap(), Expression::UnitAnnotation { .. }));
+        assert!(parser.parse("1 234k").is_err());
+        // Only groups of exactly three after one to three leading digits
+        assert!(parser.parse("1234 567").is_err());
+        assert!(parser.parse("1 23").is_err());
+        assert!(parser.parse("1 2345").is_err());
+        assert!(parser.parse("x1 234").is_err());
+
+        assert_eq!(join_space_groups("nthroot(1 000 000, 3)"), "nthroot(1000000, 3)");
+        assert_eq!(join_space_groups("5 + 100 200"), "5 + 100200");
+        assert!(Parser::new().parse("1 234").is_err());
+    }
+
+    #[test]
+    fn test_parse_multi_conversion() {
+        let parser = Parser::new();
+        match parser.parse("100 USD to INR, EUR, gbp").unwrap() {
+            Expression::MultiConversion { target_currencies, .. } => {
+                assert_eq!(target_currencies, vec![Currency::new("INR").unwrap(), Currency::new("EUR").unwrap(), Currency::new("GBP").unwrap()]);
+            }
+            other => panic!("Expected MultiConversion, got {:?}", other),
+        }
+        // A single target with trailing punctuation is still a plain conversion
+        assert!(matches!(parser.parse("100 USD to INR,").unwrap(), Expression::CurrencyConversion { .. }));
+        assert!(matches!(parser.parse("100 USD to INR, EUR,").unwrap(), Expression::MultiConversion { .. }));
+        assert_eq!(parser.parse("100 USD to INR, 5").unwrap_err(), "Invalid currency: 5");
+    }
+
+    #[test]
+    fn test_parse_convert_function() {
+        let parser = Parser::new();
+        for input in ["convert(100, \"USD\", \"INR\")", "CONVERT(100, usd, 'inr')", "convert(100, $, ₹)"] {
+            match parser.parse(input).unwrap() {
+                Expression::CurrencyConversion { source, target_currency } => {
+                    assert_eq!(target_currency, "INR");
+                    assert!(matches!(*source, Expression::CurrencyAnnotation { ref currency, .. } if currency == "USD"), "{:?}", input);
+                }
+                other => panic!("Expected CurrencyConversion for {:?}, got {:?}", input, other),
+            }
+        }
+        // A self-contained operand, so the division applies to the converted amount
+        assert!(matches!(parser.parse("convert(100, \"USD\", \"INR\") / 4").unwrap(), Expression::BinaryOp { op: Operator::Divide, ref left, .. }
+            if matches!(**left, Expression::CurrencyConversion { .. })));
+
+        let usage = "convert expects an amount and two currencies, as in convert(100, \"USD\", \"INR\")";
+        assert_eq!(parser.parse("convert(100, \"USD\")").unwrap_err(), usage);
+        assert_eq!(parser.parse("convert(100, \"USD\", \"INR\", 5)").unwrap_err(), usage);
+        assert_eq!(parser.parse("convert(100, \"US\", \"INR\")").unwrap_err(), "Invalid currency: US");
+        assert_eq!(parser.parse("convert(100, \"USD\", \"INR\"").unwrap_err(), "Expected closing parenthesis");
+    }
+
+    #[test]
+    fn test_parse_chained_conversion() {
+        let parser = Parser::new();
+        match parser.parse("100 USD to EUR to INR").unwrap() {
+            Expression::CurrencyConversion { source, target_currency } => {
+                assert_eq!(target_currency, "INR");
+                assert!(matches!(*source, Expression::CurrencyConversion { ref target_currency, .. } if target_currency == "EUR"));
+            }
+            other => panic!("Expected CurrencyConversion, got {:?}", other),
+        }
+        assert!(matches!(parser.parse("100 USD to EUR to INR, USD").unwrap(), Expression::MultiConversion { ref source, .. }
+            if matches!(**source, Expression::CurrencyConversion { .. })));
+        // Several targets end the chain
+        assert_eq!(parser.parse("100 USD to INR, EUR to USD").unwrap_err(), "Unexpected token: to");
+        assert!(parser.is_incomplete("100 USD to EUR to"));
+    }
+
+    #[test]
+    fn test_prefix_symbol_and_grouping_commas() {
+        let parser = Parser::new();
+        for input in ["$100", "$ 100", "$1,234.56", "€1,000,000", "USD 100", "inr 2.5k"] {
+            assert!(matches!(parser.parse(input).unwrap(), Expression::CurrencyAnnotation { .. }), "{:?}", input);
+        }
+        match parser.parse("$1,234.56 to EUR").unwrap() {
+            Expression::CurrencyConversion { source, target_currency } => {
+                assert_eq!(target_currency, "EUR");
+                assert!(matches!(*source, Expression::CurrencyAnnotation { ref value, ref currency }
+                    if *currency == "USD" && matches!(**value, Expression::Number(n) if n == 1234.56)));
+            }
+            other => panic!("Expected CurrencyConversion, got {:?}", other),
+        }
+        match parser.parse("USD 100 to INR").unwrap() {
+            Expression::CurrencyConversion { source, target_currency } => {
+                assert_eq!(target_currency, "INR");
+                assert!(matches!(*source, Expression::CurrencyAnnotation { ref currency, .. } if *currency == "USD"));
+            }
+            other => panic!("Expected CurrencyConversion, got {:?}", other),
+        }
+        // A code with no amount after it is still a variable
+        assert!(matches!(parser.parse("usd").unwrap(), Expression::Variable(ref name) if name == "usd"));
+
+        assert_eq!(strip_grouping_commas("1,234,567.89 + 2"), "1234567.89 + 2");
+        assert_eq!(strip_grouping_commas("12,34,567"), "1234567");
+        // Not grouping: argument lists, short or long groups, trailing prose commas
+        assert_eq!(strip_grouping_commas("ncr(100,200)"), "ncr(100,200)");
+        assert_eq!(strip_grouping_commas("1,23"), "1,23");
+        assert_eq!(strip_grouping_commas("1,2345"), "1,2345");
+        assert_eq!(strip_grouping_commas("1234,567"), "1234,567");
+        assert_eq!(strip_grouping_commas("1,234,56,789"), "1,234,56,789");
+        assert_eq!(strip_grouping_commas("100 usd to inr,"), "100 usd to inr,");
+
+        // Inside parentheses that aren't a call, commas can only be grouping
+        assert_eq!(strip_grouping_commas("2 * (1,000 + 5)"), "2 * (1000 + 5)");
+        assert_eq!(strip_grouping_commas("($1,234.56) to EUR"), "($1234.56) to EUR");
+        // In a call whose arguments are separated by `, ` they're grouping too
+        assert_eq!(strip_grouping_commas("max(1,000, 2)"), "max(1000, 2)");
+        assert_eq!(strip_grouping_commas("max(2, min(1,000, 5))"), "max(2, min(1000, 5))");
+        assert_eq!(strip_grouping_commas("log10 (1,000, 2)"), "log10 (1000, 2)");
+        assert_eq!(strip_grouping_commas("max((1,000), 2)"), "max((1000), 2)");
+    }
 }
@@ -0,0 +1,284 @@
+use crate::calc::{logical_line, Calculator, LineAccess};
+use std::collections::{HashMap, HashSet};
+
+/// A whole sheet of lines with their results, recomputing only what an edit affects.
+///
+/// Each line remembers which variables it read and wrote when it was last evaluated.
+/// After an edit, only the edited line and lines reading a variable whose value changed
+/// are evaluated again. Commands, the memory register and `sum` depend on more than the
+/// variables a line reads, so a sheet using them is always recomputed in full.
+#[derive(Debug)]
+pub struct Sheet {
+    calculator: Calculator,
+    lines: Vec<String>,
+    entries: Vec<Entry>,
+    evaluations: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    // The expression the line was evaluated as, joined with any lines it continues
+    text: Option<String>,
+    result: Option<String>,
+    access: LineAccess,
+}
+
+impl Sheet {
+    pub fn new(calculator: Calculator) -> Self {
+        Self {
+            calculator,
+            lines: Vec::new(),
+            entries: Vec::new(),
+            evaluations: 0,
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn results(&self) -> Vec<Option<String>> {
+        self.entries.iter().map(|entry| entry.result.clone()).collect()
+    }
+
+    pub fn result(&self, line_idx: usize) -> Option<&str> {
+        self.entries.get(line_idx)?.result.as_deref()
+    }
+
+    /// Number of lines evaluated so far
+    pub fn evaluations(&self) -> usize {
+        self.evaluations
+    }
+
+    pub fn calculator_mut(&mut self) -> &mut Calculator {
+        &mut self.calculator
+    }
+
+    /// Replace every line and recompute the whole sheet
+    pub fn set_lines(&mut self, lines: Vec<String>) {
+        self.lines = lines;
+        self.recompute_all();
+    }
+
+    pub fn set_line(&mut self, line_idx: usize, text: &str) {
+        self.lines[line_idx] = text.to_string();
+        self.recompute_from(line_idx);
+    }
+
+    pub fn insert_line(&mut self, line_idx: usize, text: &str) {
+        self.lines.insert(line_idx, text.to_string());
+        self.entries.insert(line_idx, Entry::default());
+        self.recompute_from(line_idx);
+    }
+
+    pub fn remove_line(&mut self, line_idx: usize) {
+        self.lines.remove(line_idx);
+        let removed = self.entries.remove(line_idx);
+
+        // Whatever the removed line defined is gone for the lines below it
+        let changed: HashSet<String> = removed.access.writes.into_iter().map(|(name, _)| name).collect();
+        if self.entries.iter().any(|entry| entry.access.volatile) || removed.access.volatile {
+            self.recompute_all();
+        } else {
+            self.recompute(line_idx, changed, false);
+        }
+    }
+
+    /// Re-evaluate line `line_idx` and every later line that depends on what it writes
+    pub fn recompute_from(&mut self, line_idx: usize) {
+        if self.entries.iter().any(|entry| entry.access.volatile) {
+            self.recompute_all();
+        } else {
+            self.recompute(line_idx, HashSet::new(), true);
+        }
+    }
+
+    pub fn recompute_all(&mut self) {
+        self.entries = vec![Entry::default(); self.lines.len()];
+        self.calculator.reset_memory();
+        self.calculator.clear_section();
+        self.calculator.replace_variables(HashMap::new());
+        for idx in 0..self.lines.len() {
+            self.evaluate(idx);
+        }
+    }
+
+    // Walk down from `start`, evaluating the start line (if `include_start`) and any line
+    // that read a variable in `changed`. Variables are restored to their value just above
+    // each line, so a later reassignment doesn't leak upwards.
+    fn recompute(&mut self, start: usize, mut changed: HashSet<String>, include_start: bool) {
+        // Only a sheet without `sum` lines gets here, see `recompute_from`
+        self.calculator.clear_section();
+        let mut env = HashMap::new();
+        for entry in &self.entries[..start] {
+            env.extend(entry.access.writes.iter().cloned());
+        }
+
+        for idx in start..self.lines.len() {
+            let entry = &self.entries[idx];
+            // Adding or removing a `\` above changes what a line is joined with, and so its
+            // expression, whether or not it's continued now
+            let stale = (include_start && idx == start)
+                || logical_line(&self.lines, idx) != entry.text
+                || entry.access.reads.iter().any(|name| changed.contains(name));
+            if stale {
+                let old_writes = entry.access.writes.clone();
+                self.calculator.replace_variables(env.clone());
+                self.evaluate(idx);
+
+                if self.entries[idx].access.volatile {
+                    return self.recompute_all();
+                }
+                let new_writes = &self.entries[idx].access.writes;
+                if *new_writes != old_writes {
+                    changed.extend(old_writes.into_iter().map(|(name, _)| name));
+                    changed.extend(new_writes.iter().map(|(name, _)| name.clone()));
+                }
+            }
+            env.extend(self.entries[idx].access.writes.iter().cloned());
+        }
+
+        self.calculator.replace_variables(env);
+    }
+
+    fn evaluate(&mut self, idx: usize) {
+        let text = logical_line(&self.lines, idx);
+        let result = text.as_deref().and_then(|text| self.calculator.evaluate_section_line(text)).map(|(result, _)| result);
+        self.entries[idx] = Entry {
+            text,
+            result,
+            access: self.calculator.take_access(),
+        };
+        self.evaluations += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::CurrencyConverter;
+
+    fn create_sheet(lines: &[&str]) -> Sheet {
+        let mut sheet = Sheet::new(Calculator::with_converter(CurrencyConverter::offline()));
+        sheet.set_lines(lines.iter().map(|line| line.to_string()).collect());
+        sheet
+    }
+
+    #[test]
+    fn test_initial_results() {
+        let sheet = create_sheet(&["a = 2", "b = 10", "a * 3", "", "b + 1"]);
+        assert_eq!(sheet.evaluations(), 5);
+        assert_eq!(sheet.result(2), Some("6"));
+        assert_eq!(sheet.result(3), None);
+        assert_eq!(sheet.result(4), Some("11"));
+    }
+
+    #[test]
+    fn test_edit_recomputes_only_dependents() {
+        let mut sheet = create_sheet(&["a = 2", "b = 10", "a * 3", "b + 1"]);
+        let before = sheet.evaluations();
+
+        sheet.set_line(1, "b = 20");
+        // The edited line and `b + 1`, but not `a * 3`
+        assert_eq!(sheet.evaluations(), before + 2);
+        assert_eq!(sheet.result(2), Some("6"));
+        assert_eq!(sheet.result(3), Some("21"));
+    }
+
+    #[test]
+    fn test_edit_without_dependents() {
+        let mut sheet = create_sheet(&["a = 2", "100 / 4", "a * 3"]);
+        let before = sheet.evaluations();
+
+        sheet.set_line(1, "100 / 5");
+        assert_eq!(sheet.evaluations(), before + 1);
+        assert_eq!(sheet.results(), vec![Some("2".to_string()), Some("20".to_string()), Some("6".to_string())]);
+    }
+
+    #[test]
+    fn test_changes_propagate_through_chains() {
+        let mut sheet = create_sheet(&["a = 2", "b = a * 10", "c = b + 1", "c"]);
+        sheet.set_line(0, "a = 3");
+        assert_eq!(sheet.result(3), Some("31"));
+    }
+
+    #[test]
+    fn test_defining_a_missing_variable_fixes_later_lines() {
+        let mut sheet = create_sheet(&["x * 2", "", "y + 1"]);
+        assert!(sheet.result(2).unwrap().starts_with("Error:"));
+
+        sheet.set_line(1, "y = 4");
+        assert_eq!(sheet.result(2), Some("5"));
+        // Lines above a definition don't see it
+        assert!(sheet.result(0).unwrap().starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_later_reassignment_does_not_leak_upwards() {
+        let mut sheet = create_sheet(&["x = 1", "x + 1", "x = 100", "x + 1"]);
+        sheet.set_line(1, "x + 2");
+        assert_eq!(sheet.result(1), Some("3"));
+        assert_eq!(sheet.result(3), Some("101"));
+    }
+
+    #[test]
+    fn test_insert_and_remove_lines() {
+        let mut sheet = create_sheet(&["a = 2", "a * 3"]);
+        sheet.insert_line(1, "a = 5");
+        assert_eq!(sheet.result(2), Some("15"));
+
+        sheet.remove_line(1);
+        assert_eq!(sheet.result(1), Some("6"));
+        sheet.remove_line(0);
+        assert!(sheet.result(0).unwrap().starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_memory_forces_full_recompute() {
+        let mut sheet = create_sheet(&["5", "m+", "mr * 2", "7"]);
+        let before = sheet.evaluations();
+        sheet.set_line(0, "6");
+        assert_eq!(sheet.evaluations(), before + 4);
+        assert_eq!(sheet.result(2), Some("12"));
+    }
+
+    #[test]
+    fn test_continued_lines() {
+        let mut sheet = create_sheet(&["a = 1 + \\", "2 + \\", "3", "a * 10"]);
+        assert_eq!(sheet.results(), vec![None, None, Some("6".to_string()), Some("60".to_string())]);
+
+        sheet.set_line(0, "a = 5 + \\");
+        assert_eq!(sheet.results(), vec![None, None, Some("10".to_string()), Some("100".to_string())]);
+    }
+
+    #[test]
+    fn test_sum_of_section() {
+        let mut sheet = create_sheet(&["100", "200", "sum"]);
+        assert_eq!(sheet.result(2), Some("300"));
+
+        // The `sum` line follows edits to the lines it adds up
+        sheet.set_line(0, "150");
+        assert_eq!(sheet.result(2), Some("350"));
+        sheet.insert_line(2, "");
+        assert_eq!(sheet.result(3), Some("0"));
+    }
+
+    #[test]
+    fn test_removing_a_continuation() {
+        let lines = ["a = 1 + \\", "4", "a"];
+        let mut sheet = create_sheet(&lines);
+        assert_eq!(sheet.results(), vec![None, Some("5".to_string()), Some("5".to_string())]);
+
+        sheet.set_line(0, "a = 1");
+        let mut calc = Calculator::with_converter(CurrencyConverter::offline());
+        let expected = calc.evaluate_all(sheet.lines());
+        assert_eq!(sheet.results(), expected);
+        assert_eq!(sheet.results(), vec![Some("1".to_string()), Some("4".to_string()), Some("1".to_string())]);
+
+        // And adding one back, or removing the line that had it
+        sheet.set_line(0, "a = 1 + \\");
+        assert_eq!(sheet.results(), vec![None, Some("5".to_string()), Some("5".to_string())]);
+        sheet.remove_line(0);
+        assert_eq!(sheet.results(), vec![Some("4".to_string()), Some("Error: Undefined variable: a".to_string())]);
+    }
+}
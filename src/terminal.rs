@@ -0,0 +1,84 @@
+use crossterm::{
+    cursor::Show,
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::io;
+use std::panic;
+
+/// Puts the terminal into raw mode on the alternate screen and restores it when dropped,
+/// including when unwinding from a panic.
+pub struct TerminalGuard {
+    teardown: Option<Box<dyn FnOnce()>>,
+}
+
+impl TerminalGuard {
+    pub fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+
+        // The panic hook prints before unwinding reaches our Drop, so restore the terminal
+        // first or the message lands on the alternate screen and is lost
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            default_hook(info);
+        }));
+
+        Ok(Self::with_teardown(restore_terminal))
+    }
+
+    fn with_teardown(teardown: impl FnOnce() + 'static) -> Self {
+        Self {
+            teardown: Some(Box::new(teardown)),
+        }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Some(teardown) = self.teardown.take() {
+            teardown();
+        }
+    }
+}
+
+// Best effort: there's nothing useful to do if restoring fails
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn counting_guard(count: &Arc<AtomicUsize>) -> TerminalGuard {
+        let count = Arc::clone(count);
+        TerminalGuard::with_teardown(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn test_teardown_runs_once_on_drop() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let guard = counting_guard(&count);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        drop(guard);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_teardown_runs_when_panicking() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let result = panic::catch_unwind(|| {
+            let _guard = counting_guard(&count);
+            panic!("render failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
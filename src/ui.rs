@@ -2,26 +2,132 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
+    widgets::{Block, Borders, Clear, Paragraph},
     Frame,
 };
 
-use crate::editor::Editor;
+use std::ops::Range;
+
+use indumi::calc::ResultKind;
+use indumi::editor::{match_paren, unbalanced_positions, Editor};
+
+// Below this many columns the panels are stacked instead of side by side
+const COMPACT_WIDTH: u16 = 80;
 
 pub fn render_ui(f: &mut Frame, editor: &Editor) {
+    let (input_area, results_area) = panel_areas(f.area());
+
+    // Each panel scrolls to keep the cursor line in view. Side by side they're the same
+    // height, so results stay on the rows of their lines.
+    let input_scroll = scroll_offset(editor.cursor_line, input_area.height);
+    render_input_panel(f, editor, input_area, input_scroll);
+    render_results_panel(f, editor, results_area, scroll_offset(editor.cursor_line, results_area.height));
+    render_completion_popup(f, editor, input_area, input_scroll);
+}
+
+// Input and results side by side, or on narrow terminals input above results
+fn panel_areas(area: Rect) -> (Rect, Rect) {
+    let direction = if area.width < COMPACT_WIDTH {
+        Direction::Vertical
+    } else {
+        Direction::Horizontal
+    };
     let chunks = Layout::default()
-        .direction(Direction::Horizontal)
+        .direction(direction)
         .constraints([
             Constraint::Percentage(60),
             Constraint::Percentage(40),
         ])
-        .split(f.area());
+        .split(area);
+    (chunks[0], chunks[1])
+}
+
+// Lines scrolled off the top so the cursor line fits in a bordered panel `height` rows tall
+fn scroll_offset(cursor_line: usize, height: u16) -> u16 {
+    let visible = height.saturating_sub(2).max(1) as usize;
+    cursor_line.saturating_sub(visible - 1) as u16
+}
+
+// One span per character: unbalanced parentheses in red, `matching` (the partner of the
+// parenthesis under the cursor) highlighted, and the part to blame for a parse error underlined
+fn paren_styled_line(line: &str, style: Style, matching: Option<usize>, error: Option<Range<usize>>) -> Line<'static> {
+    let unbalanced = unbalanced_positions(line);
+    let spans: Vec<Span> = line
+        .char_indices()
+        .map(|(pos, ch)| {
+            let style = if error.as_ref().is_some_and(|error| error.contains(&pos)) {
+                style.fg(Color::Rgb(255, 80, 80)).add_modifier(Modifier::UNDERLINED)
+            } else if Some(pos) == matching {
+                style.bg(Color::Rgb(0, 120, 120))  // Dark cyan
+            } else if unbalanced.contains(&pos) {
+                style.fg(Color::Rgb(255, 80, 80))  // Soft red
+            } else {
+                style
+            };
+            Span::styled(ch.to_string(), style)
+        })
+        .collect();
+    Line::from(spans)
+}
+
+// Give the selected bytes of a line built by `paren_styled_line` a background
+fn highlight_selection(line: &mut Line, text: &str, selected: Range<usize>) {
+    for (span, (pos, _)) in line.spans.iter_mut().zip(text.char_indices()) {
+        if selected.contains(&pos) {
+            span.style = span.style.bg(Color::Rgb(60, 60, 140));  // Muted blue
+        }
+    }
+}
 
-    render_input_panel(f, editor, chunks[0]);
-    render_results_panel(f, editor, chunks[1]);
+// Shown just below the word being typed; Tab accepts the highlighted first entry
+fn render_completion_popup(f: &mut Frame, editor: &Editor, input_area: Rect, scroll: u16) {
+    const MAX_ITEMS: usize = 5;
+
+    let candidates = editor.completions();
+    let Some((word_start, _)) = editor.word_before_cursor() else {
+        return;
+    };
+    if candidates.is_empty() {
+        return;
+    }
+
+    let items: Vec<Line> = candidates
+        .iter()
+        .take(MAX_ITEMS)
+        .enumerate()
+        .map(|(idx, name)| {
+            let style = if idx == 0 {
+                Style::default().fg(Color::Black).bg(Color::Rgb(0, 255, 255))
+            } else {
+                Style::default().fg(Color::Rgb(255, 255, 255))
+            };
+            Line::from(Span::styled(name.clone(), style))
+        })
+        .collect();
+
+    let width = candidates.iter().map(|c| c.len()).max().unwrap_or(0) as u16 + 2;
+    let height = items.len() as u16 + 2;
+    let x = input_area.x + 1 + word_start as u16;
+    let y = input_area.y + 2 + (editor.cursor_line as u16).saturating_sub(scroll);
+
+    // Keep the popup inside the input panel
+    let area = Rect::new(x, y, width, height).intersection(input_area);
+    if area.width < 3 || area.height < 3 {
+        return;
+    }
+
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Rgb(0, 255, 255))),
+        ),
+        area,
+    );
 }
 
-fn render_input_panel(f: &mut Frame, editor: &Editor, area: Rect) {
+fn render_input_panel(f: &mut Frame, editor: &Editor, area: Rect, scroll: u16) {
     let mut lines: Vec<Line> = editor
         .lines
         .iter()
@@ -34,68 +140,107 @@ fn render_input_panel(f: &mut Frame, editor: &Editor, area: Rect) {
             } else {
                 Style::default().fg(Color::Rgb(150, 150, 150))  // Medium gray
             };
-            Line::from(Span::styled(line.clone(), style))
+            let mut styled = paren_styled_line(line, style, None, None);
+            if let Some(selected) = editor.selection_in_line(idx) {
+                highlight_selection(&mut styled, line, selected);
+            }
+            styled
         })
         .collect();
 
-    // Add cursor indicator
+    // Add cursor indicator, highlighting the partner of a parenthesis under it
     if editor.cursor_line < lines.len() {
         let cursor_line = &editor.lines[editor.cursor_line];
-        let before_cursor = &cursor_line[..editor.cursor_col];
-        let at_cursor = cursor_line
-            .chars()
-            .nth(editor.cursor_col)
-            .unwrap_or(' ');
-        let after_cursor = &cursor_line[editor.cursor_col.min(cursor_line.len())..];
-
-        lines[editor.cursor_line] = Line::from(vec![
-            Span::styled(
-                before_cursor.to_string(),
-                Style::default().fg(Color::Rgb(255, 255, 255))
-            ),
-            Span::styled(
-                at_cursor.to_string(),
-                Style::default()
-                    .fg(Color::Black)
-                    .bg(Color::Rgb(255, 255, 255))
-                    .add_modifier(Modifier::BOLD),
-            ),
-            Span::styled(
-                after_cursor.to_string(),
-                Style::default().fg(Color::Rgb(255, 255, 255))
-            ),
-        ]);
+        let matching = match_paren(cursor_line, editor.cursor_col);
+        let error = editor.current_line_error_span();
+        let mut line = paren_styled_line(cursor_line, Style::default().fg(Color::Rgb(255, 255, 255)), matching, error);
+        if let Some(selected) = editor.selection_in_line(editor.cursor_line) {
+            highlight_selection(&mut line, cursor_line, selected);
+        }
+
+        let cursor_style = Style::default()
+            .fg(Color::Black)
+            .bg(Color::Rgb(255, 255, 255))
+            .add_modifier(Modifier::BOLD);
+        // One span per character, so the cursor's span is found by counting characters
+        let cursor_idx = cursor_line[..editor.cursor_col.min(cursor_line.len())].chars().count();
+        match line.spans.get_mut(cursor_idx) {
+            Some(span) => span.style = cursor_style,
+            None => line.spans.push(Span::styled(" ", cursor_style)),
+        }
+        lines[editor.cursor_line] = line;
     }
 
+    // Red while the cursor line doesn't parse, so mistakes show before moving on
+    let border_color = if editor.current_line_is_valid() {
+        Color::Rgb(0, 255, 255)  // Bright cyan
+    } else {
+        Color::Rgb(255, 80, 80)  // Soft red
+    };
+
+    // A selection that evaluates shows its result in place of the usual title
+    let title = match editor.evaluate_selection() {
+        Some(result) => format!("Selection = {}", result),
+        None => "Indumi Calculator (Ctrl+C to quit)".to_string(),
+    };
+
     let paragraph = Paragraph::new(lines)
+        .scroll((scroll, 0))
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .title("Indumi Calculator (Ctrl+C to quit)")
-                .border_style(Style::default().fg(Color::Rgb(0, 255, 255)))  // Bright cyan
-                .title_style(Style::default().fg(Color::Rgb(0, 255, 255)).add_modifier(Modifier::BOLD)),
+                .title(title)
+                .border_style(Style::default().fg(border_color))
+                .title_style(Style::default().fg(border_color).add_modifier(Modifier::BOLD)),
         );
 
     f.render_widget(paragraph, area);
 }
 
-fn render_results_panel(f: &mut Frame, editor: &Editor, area: Rect) {
-    let results: Vec<Line> = editor
-        .lines
+fn render_results_panel(f: &mut Frame, editor: &Editor, area: Rect, scroll: u16) {
+    let evaluated = editor.results();
+    let kinds = editor.result_kinds();
+    // Amounts line up on their last digit; errors, commands and the like stay as they are
+    let amounts: Vec<Option<&str>> = evaluated
         .iter()
-        .map(|line| {
-            if let Some(result) = editor.calculator.borrow_mut().evaluate_line(line) {
-                // Check if result is an error
-                let (text, color) = if result.starts_with("Error:") || result.starts_with("Parse error:") {
-                    (format!("= {}", result), Color::Rgb(255, 80, 80))  // Bright red for errors
-                } else {
-                    (format!("= {}", result), Color::Rgb(0, 255, 0))  // Bright green for results
+        .zip(&kinds)
+        .map(|(result, kind)| match kind {
+            Some(ResultKind::Number | ResultKind::Currency | ResultKind::Unit) => result.as_deref().and_then(|r| r.lines().next()),
+            _ => None,
+        })
+        .collect();
+    let aligned = align_amounts(&amounts);
+    let warnings = editor.result_warnings();
+
+    let results: Vec<Line> = evaluated
+        .iter()
+        .zip(kinds)
+        .zip(aligned)
+        .zip(warnings)
+        .map(|(((result, kind), aligned), warning)| {
+            if let Some(result) = result {
+                // Extra result lines (e.g. the inverse rate) are hints, shown dimmed on the
+                // same row so results stay aligned with their input lines
+                let (result, hint) = match result.split_once('\n') {
+                    Some((result, hint)) => (result.to_string(), Some(hint.replace('\n', "  "))),
+                    None => (result.clone(), None),
                 };
+                let result = aligned.unwrap_or(result);
 
-                Line::from(Span::styled(
-                    text,
-                    Style::default().fg(color).add_modifier(Modifier::BOLD),
-                ))
+                let (glyph, color) = kind_style(kind.unwrap_or(ResultKind::Number));
+                let text = if result == "…" { result } else { format!("= {}", result) };
+
+                let mut spans = vec![
+                    Span::styled(format!("{} ", glyph), Style::default().fg(color)),
+                    Span::styled(text, Style::default().fg(color).add_modifier(Modifier::BOLD)),
+                ];
+                if let Some(hint) = hint {
+                    spans.push(Span::styled(format!("  {}", hint), Style::default().fg(Color::DarkGray)));
+                }
+                if let Some(warning) = warning {
+                    spans.push(Span::styled(format!("  Warning: {}", warning), Style::default().fg(Color::Yellow)));
+                }
+                Line::from(spans)
             } else {
                 Line::from(Span::styled("", Style::default()))
             }
@@ -103,6 +248,7 @@ fn render_results_panel(f: &mut Frame, editor: &Editor, area: Rect) {
         .collect();
 
     let paragraph = Paragraph::new(results)
+        .scroll((scroll, 0))
         .block(
             Block::default()
                 .borders(Borders::ALL)
@@ -113,3 +259,96 @@ fn render_results_panel(f: &mut Frame, editor: &Editor, area: Rect) {
 
     f.render_widget(paragraph, area);
 }
+
+// Pad results on the left so the last digits of their amounts share a column, lining up a
+// budget's digits. Whatever follows the digits trails on: a unit, a currency symbol placed
+// after the amount, or an estimate or share like `(1.5 K)`.
+fn align_amounts(results: &[Option<&str>]) -> Vec<Option<String>> {
+    let amount_width = |result: &str| {
+        let amount = result.split(" (").next().unwrap_or(result);
+        let chars: Vec<char> = amount.chars().collect();
+        chars.iter().rposition(char::is_ascii_digit).map_or(chars.len(), |last| last + 1)
+    };
+    let width = results.iter().flatten().map(|result| amount_width(result)).max().unwrap_or(0);
+    results
+        .iter()
+        .map(|result| result.map(|result| format!("{}{}", " ".repeat(width - amount_width(result)), result)))
+        .collect()
+}
+
+// A glyph in front of each result and its colour, so currency amounts, units, true/false
+// and errors can be told apart at a glance
+fn kind_style(kind: ResultKind) -> (&'static str, Color) {
+    match kind {
+        ResultKind::Number => ("#", Color::Rgb(0, 255, 0)),         // Bright green
+        ResultKind::Currency => ("¤", Color::Rgb(255, 215, 0)),     // Gold
+        ResultKind::Unit => ("~", Color::Rgb(0, 200, 255)),         // Cyan
+        ResultKind::Boolean => ("?", Color::Rgb(180, 140, 255)),    // Violet
+        ResultKind::Command => ("›", Color::Rgb(150, 150, 150)),    // Gray
+        ResultKind::Error => ("!", Color::Rgb(255, 80, 80)),        // Bright red
+        ResultKind::Pending => (" ", Color::Rgb(150, 150, 150)),    // Gray while the line is being typed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_align_amounts() {
+        let results = [Some("1,500 (1.5 K)"), None, Some("₹ 8,350 (8.3 K)"), Some("42"), Some("$ 12.50"), Some("3 apples")];
+        assert_eq!(
+            align_amounts(&results),
+            vec![
+                Some("  1,500 (1.5 K)".to_string()),
+                None,
+                Some("₹ 8,350 (8.3 K)".to_string()),
+                Some("     42".to_string()),
+                Some("$ 12.50".to_string()),
+                Some("      3 apples".to_string()),
+            ]
+        );
+        assert_eq!(align_amounts(&[None, None]), vec![None, None]);
+    }
+
+    #[test]
+    fn test_each_result_kind_has_its_own_style() {
+        let kinds = [
+            ResultKind::Number,
+            ResultKind::Currency,
+            ResultKind::Unit,
+            ResultKind::Boolean,
+            ResultKind::Command,
+            ResultKind::Error,
+            ResultKind::Pending,
+        ];
+        let glyphs: std::collections::HashSet<&str> = kinds.iter().map(|&kind| kind_style(kind).0).collect();
+        assert_eq!(glyphs.len(), kinds.len());
+        assert_eq!(kind_style(ResultKind::Error).1, Color::Rgb(255, 80, 80));
+    }
+
+    #[test]
+    fn test_panel_areas_side_by_side() {
+        let (input, results) = panel_areas(Rect::new(0, 0, 120, 40));
+        assert_eq!((input.x, input.width, input.height), (0, 72, 40));
+        assert_eq!((results.x, results.width, results.height), (72, 48, 40));
+    }
+
+    #[test]
+    fn test_panel_areas_stacked_when_narrow() {
+        let (input, results) = panel_areas(Rect::new(0, 0, 60, 40));
+        assert_eq!((input.y, input.width, input.height), (0, 60, 24));
+        assert_eq!((results.y, results.width, results.height), (24, 60, 16));
+    }
+
+    #[test]
+    fn test_scroll_offset_keeps_cursor_visible() {
+        // 10 rows minus the borders leaves 8 lines
+        assert_eq!(scroll_offset(0, 10), 0);
+        assert_eq!(scroll_offset(7, 10), 0);
+        assert_eq!(scroll_offset(8, 10), 1);
+        assert_eq!(scroll_offset(30, 10), 23);
+        // A panel too small for any text still shows the cursor line at the top
+        assert_eq!(scroll_offset(5, 2), 5);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::currency::Currency;
+use crate::parser::Operator;
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+/// Result of evaluating an expression.
+///
+/// Currency amounts keep their currency code so later operations (and formatting)
+/// know what they are holding. Numbers written with a free-text unit (`50 apples`)
+/// keep it the same way. Booleans come from comparisons. Exact integers
+/// (factorials, nCr) stay exact until an operation needs a fraction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Currency(f64, Currency),
+    Unit(f64, String),
+    Bool(bool),
+    BigInt(BigInt),
+}
+
+impl Value {
+    /// Plain numeric value, dropping any currency. `true`/`false` become `1`/`0`.
+    pub fn as_number(&self) -> f64 {
+        match self {
+            Value::Number(n) | Value::Currency(n, _) | Value::Unit(n, _) => *n,
+            Value::Bool(b) => if *b { 1.0 } else { 0.0 },
+            Value::BigInt(n) => n.to_f64().unwrap_or(f64::INFINITY),
+        }
+    }
+
+    // Whole numbers as exact integers; fractions and currency amounts have no exact form
+    fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::BigInt(n) => Some(n.clone()),
+            Value::Number(n) if n.fract() == 0.0 => BigInt::from_f64(*n),
+            _ => None,
+        }
+    }
+
+    pub fn currency(&self) -> Option<&Currency> {
+        match self {
+            Value::Currency(_, currency) => Some(currency),
+            _ => None,
+        }
+    }
+
+    pub fn unit(&self) -> Option<&str> {
+        match self {
+            Value::Unit(_, unit) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// Apply an arithmetic operator. Currency amounts combine with plain numbers
+    /// (scaling, adding a flat amount) and with the same currency, but two different
+    /// currencies must be converted before they can be mixed. Units follow the same rules,
+    /// except that different units only divide, into a rate like `km/h`.
+    pub fn apply(op: Operator, left: Value, right: Value) -> Result<Value, String> {
+        // Booleans take part in arithmetic as 1/0, so `(x > 5) * 100` works
+        let left = left.without_bool();
+        let right = right.without_bool();
+
+        if matches!(left, Value::BigInt(_)) || matches!(right, Value::BigInt(_)) {
+            if let (Some(a), Some(b)) = (left.as_bigint(), right.as_bigint()) {
+                if let Some(exact) = apply_exact(op, &a, &b) {
+                    return Ok(Value::BigInt(exact));
+                }
+            }
+        }
+
+        // Past this point exact integers fall back to floating point
+        let left = left.without_bigint();
+        let right = right.without_bigint();
+
+        match (left, right) {
+            (Value::Currency(a, c1), Value::Currency(b, c2)) => {
+                if c1 != c2 {
+                    return Err(format!("Cannot combine {} and {} without converting first", c1, c2));
+                }
+                match op {
+                    Operator::Add => Ok(Value::Currency(a + b, c1)),
+                    Operator::Subtract => Ok(Value::Currency(a - b, c1)),
+                    Operator::Divide => Ok(Value::Number(a / b)),
+                    Operator::Modulo => Ok(Value::Currency(a % b, c1)),
+                    Operator::Multiply => Err("Cannot multiply two currency amounts".to_string()),
+                    Operator::Power => Err("Cannot raise a currency amount to a power".to_string()),
+                    _ => Err("Not an arithmetic operator".to_string()),
+                }
+            }
+            (Value::Currency(a, currency), Value::Number(b)) => {
+                if matches!(op, Operator::Power) {
+                    return Err("Cannot raise a currency amount to a power".to_string());
+                }
+                Ok(Value::Currency(apply_numbers(op, a, b)?, currency))
+            }
+            (Value::Number(a), Value::Currency(b, currency)) => match op {
+                Operator::Add | Operator::Subtract | Operator::Multiply => {
+                    Ok(Value::Currency(apply_numbers(op, a, b)?, currency))
+                }
+                _ => Err(format!("Cannot use a {} amount on the right of this operator", currency)),
+            },
+            (Value::Unit(a, u1), Value::Unit(b, u2)) => {
+                if u1 != u2 {
+                    return match op {
+                        Operator::Divide => Ok(Value::Unit(a / b, format!("{}/{}", u1, u2))),
+                        _ => Err(format!("Cannot combine {} and {}", u1, u2)),
+                    };
+                }
+                match op {
+                    Operator::Add => Ok(Value::Unit(a + b, u1)),
+                    Operator::Subtract => Ok(Value::Unit(a - b, u1)),
+                    Operator::Divide => Ok(Value::Number(a / b)),
+                    Operator::Modulo => Ok(Value::Unit(a % b, u1)),
+                    Operator::Multiply => Err(format!("Cannot multiply two amounts in {}", u1)),
+                    Operator::Power => Err(format!("Cannot raise an amount in {} to a power", u1)),
+                    _ => Err("Not an arithmetic operator".to_string()),
+                }
+            }
+            (Value::Unit(a, unit), Value::Number(b)) => {
+                if matches!(op, Operator::Power) {
+                    return Err(format!("Cannot raise an amount in {} to a power", unit));
+                }
+                Ok(Value::Unit(apply_numbers(op, a, b)?, unit))
+            }
+            (Value::Number(a), Value::Unit(b, unit)) => match op {
+                Operator::Add | Operator::Subtract | Operator::Multiply => Ok(Value::Unit(apply_numbers(op, a, b)?, unit)),
+                _ => Err(format!("Cannot use an amount in {} on the right of this operator", unit)),
+            },
+            (Value::Unit(_, unit), Value::Currency(_, currency)) | (Value::Currency(_, currency), Value::Unit(_, unit)) => {
+                Err(format!("Cannot combine {} and {}", unit, currency))
+            }
+            (left, right) => Ok(Value::Number(apply_numbers(op, left.as_number(), right.as_number())?)),
+        }
+    }
+
+    fn without_bool(self) -> Value {
+        match self {
+            Value::Bool(_) => Value::Number(self.as_number()),
+            other => other,
+        }
+    }
+
+    fn without_bigint(self) -> Value {
+        match self {
+            Value::BigInt(_) => Value::Number(self.as_number()),
+            other => other,
+        }
+    }
+}
+
+// Integer arithmetic that stays exact, or None when the result needs a fraction
+fn apply_exact(op: Operator, a: &BigInt, b: &BigInt) -> Option<BigInt> {
+    match op {
+        Operator::Add => Some(a + b),
+        Operator::Subtract => Some(a - b),
+        Operator::Multiply => Some(a * b),
+        Operator::Divide if !b.is_zero() && (a % b).is_zero() => Some(a / b),
+        Operator::Modulo if !b.is_zero() => Some(a % b),
+        _ => None,
+    }
+}
+
+fn apply_numbers(op: Operator, a: f64, b: f64) -> Result<f64, String> {
+    match op {
+        Operator::Add => Ok(a + b),
+        Operator::Subtract => Ok(a - b),
+        Operator::Multiply => Ok(a * b),
+        Operator::Divide => Ok(a / b),
+        Operator::Power => Ok(a.powf(b)),
+        Operator::Modulo => Ok(a % b),
+        _ => Err("Not an arithmetic operator".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(amount: f64) -> Value {
+        Value::Currency(amount, Currency::new("USD").unwrap())
+    }
+
+    #[test]
+    fn test_number_arithmetic() {
+        assert_eq!(Value::apply(Operator::Add, Value::Number(2.0), Value::Number(3.0)), Ok(Value::Number(5.0)));
+        assert_eq!(Value::apply(Operator::Power, Value::Number(2.0), Value::Number(3.0)), Ok(Value::Number(8.0)));
+    }
+
+    #[test]
+    fn test_same_currency_arithmetic() {
+        assert_eq!(Value::apply(Operator::Add, usd(50.0), usd(50.0)), Ok(usd(100.0)));
+        assert_eq!(Value::apply(Operator::Subtract, usd(80.0), usd(30.0)), Ok(usd(50.0)));
+        // A ratio of two amounts in the same currency is a plain number
+        assert_eq!(Value::apply(Operator::Divide, usd(100.0), usd(25.0)), Ok(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_unit_arithmetic() {
+        let apples = |n: f64| Value::Unit(n, "apples".to_string());
+        assert_eq!(Value::apply(Operator::Add, apples(50.0), apples(30.0)), Ok(apples(80.0)));
+        assert_eq!(Value::apply(Operator::Divide, apples(10.0), apples(2.0)), Ok(Value::Number(5.0)));
+        // Scaling by a plain number keeps the unit
+        assert_eq!(Value::apply(Operator::Multiply, Value::Number(3.0), apples(4.0)), Ok(apples(12.0)));
+        assert_eq!(Value::apply(Operator::Divide, apples(12.0), Value::Number(4.0)), Ok(apples(3.0)));
+
+        let oranges = Value::Unit(30.0, "oranges".to_string());
+        assert_eq!(
+            Value::apply(Operator::Add, apples(50.0), oranges),
+            Err("Cannot combine apples and oranges".to_string())
+        );
+        assert!(Value::apply(Operator::Add, apples(50.0), usd(5.0)).is_err());
+        // Dividing different units gives a rate
+        let hours = Value::Unit(2.0, "h".to_string());
+        assert_eq!(
+            Value::apply(Operator::Divide, Value::Unit(120.0, "km".to_string()), hours),
+            Ok(Value::Unit(60.0, "km/h".to_string()))
+        );
+        assert!(Value::apply(Operator::Multiply, apples(2.0), apples(3.0)).is_err());
+        assert!(Value::apply(Operator::Divide, Value::Number(2.0), apples(3.0)).is_err());
+    }
+
+    #[test]
+    fn test_mixed_currency_arithmetic_errors() {
+        let eur = Value::Currency(50.0, Currency::new("EUR").unwrap());
+        assert!(Value::apply(Operator::Add, usd(50.0), eur).is_err());
+        assert!(Value::apply(Operator::Multiply, usd(2.0), usd(3.0)).is_err());
+    }
+
+    #[test]
+    fn test_currency_and_number_arithmetic() {
+        assert_eq!(Value::apply(Operator::Divide, usd(100.0), Value::Number(4.0)), Ok(usd(25.0)));
+        assert_eq!(Value::apply(Operator::Multiply, Value::Number(3.0), usd(10.0)), Ok(usd(30.0)));
+        assert_eq!(Value::apply(Operator::Add, usd(10.0), Value::Number(5.0)), Ok(usd(15.0)));
+        assert!(Value::apply(Operator::Divide, Value::Number(100.0), usd(4.0)).is_err());
+        assert!(Value::apply(Operator::Power, usd(2.0), Value::Number(2.0)).is_err());
+    }
+
+    #[test]
+    fn test_bool_arithmetic() {
+        assert_eq!(Value::apply(Operator::Multiply, Value::Bool(true), Value::Number(100.0)), Ok(Value::Number(100.0)));
+        assert_eq!(Value::apply(Operator::Add, Value::Bool(false), usd(5.0)), Ok(usd(5.0)));
+    }
+
+    #[test]
+    fn test_as_number() {
+        assert_eq!(Value::Number(4.5).as_number(), 4.5);
+        assert_eq!(usd(12.0).as_number(), 12.0);
+        assert_eq!(Value::Bool(true).as_number(), 1.0);
+        assert_eq!(Value::Bool(false).as_number(), 0.0);
+    }
+
+    fn big(n: u64) -> Value {
+        Value::BigInt(BigInt::from(n))
+    }
+
+    #[test]
+    fn test_bigint_arithmetic_stays_exact() {
+        assert_eq!(Value::apply(Operator::Multiply, big(6), Value::Number(7.0)), Ok(big(42)));
+        assert_eq!(Value::apply(Operator::Divide, big(120), big(24)), Ok(big(5)));
+        assert_eq!(Value::apply(Operator::Subtract, Value::Number(10.0), big(3)), Ok(big(7)));
+    }
+
+    #[test]
+    fn test_bigint_falls_back_to_float() {
+        assert_eq!(Value::apply(Operator::Divide, big(7), big(2)), Ok(Value::Number(3.5)));
+        assert_eq!(Value::apply(Operator::Add, big(1), Value::Number(0.5)), Ok(Value::Number(1.5)));
+        assert_eq!(Value::apply(Operator::Multiply, big(2), usd(5.0)), Ok(usd(10.0)));
+    }
+}
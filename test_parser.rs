@@ -11,6 +11,10 @@ mod currency {
     include!("src/currency.rs");
 }
 
+mod value {
+    include!("src/value.rs");
+}
+
 mod calc {
     include!("src/calc.rs");
 }
@@ -2,9 +2,21 @@
 // These tests exercise the full system: parsing + evaluation + formatting
 
 use indumi::calc::Calculator;
+use indumi::currency::CurrencyConverter;
+use indumi::editor::Editor;
 
 async fn test_expression(expr: &str, expected_contains: &[&str], expected_not_contains: &[&str]) {
     let mut calc = Calculator::new().await.expect("Failed to create calculator");
+    check_expression(&mut calc, expr, expected_contains, expected_not_contains);
+}
+
+// Like test_expression, but with the built-in rates, for checking converted amounts
+fn test_offline_expression(expr: &str, expected_contains: &[&str], expected_not_contains: &[&str]) {
+    let mut calc = Calculator::with_converter(CurrencyConverter::offline());
+    check_expression(&mut calc, expr, expected_contains, expected_not_contains);
+}
+
+fn check_expression(calc: &mut Calculator, expr: &str, expected_contains: &[&str], expected_not_contains: &[&str]) {
     let result = calc.evaluate_line(expr);
 
     assert!(result.is_some(), "Expression '{}' returned None", expr);
@@ -45,6 +57,15 @@ async fn test_operator_precedence() {
     test_expression("10 - 2 * 3", &["4"], &["24", "Error"]).await; // Should be 10 - 6 = 4
 }
 
+#[tokio::test]
+async fn test_left_associative_chains() {
+    test_expression("10 - 3 - 2", &["5"], &["9", "Error"]).await; // (10 - 3) - 2, not 10 - (3 - 2)
+    test_expression("100 / 5 / 2", &["10"], &["40", "Error"]).await; // (100 / 5) / 2, not 100 / (5 / 2)
+    test_expression("20 - 5 + 3", &["18"], &["12", "Error"]).await; // (20 - 5) + 3
+    test_expression("2 - 3 - 4 - 5", &["-10"], &["Error"]).await;
+    test_expression("100 USD - 30 USD - 20 USD", &["$ 50"], &["Error"]).await;
+}
+
 #[tokio::test]
 async fn test_parentheses() {
     test_expression("(2 + 3) * 4", &["20"], &["14", "Error"]).await;
@@ -90,8 +111,8 @@ async fn test_currency_conversions() {
 
 #[tokio::test]
 async fn test_currency_with_parentheses() {
-    // Note: After division, currency context is lost (it becomes a plain number)
-    test_expression("(100 USD to INR) / 4", &[], &["Error"]).await;
+    // Dividing a converted amount keeps its currency
+    test_expression("(100 USD to INR) / 4", &["₹"], &["Error"]).await;
     // But direct conversion works
     test_expression("(50 USD + 50 USD) to EUR", &["€"], &["Error"]).await;
 }
@@ -120,21 +141,22 @@ async fn test_variables() {
 async fn test_variable_with_currency() {
     let mut calc = Calculator::new().await.expect("Failed to create calculator");
 
-    // Convert and store - the variable stores the numeric value
-    // Note: Assignment expressions show numeric result, not currency formatted
+    // Convert and store - the variable keeps the currency, and the line shows it
     let result1 = calc.evaluate_line("converted = 100 USD to INR");
     assert!(result1.is_some());
     let output1 = result1.unwrap();
-    // Assignment returns the numeric value (formatted but without currency symbol)
     assert!(!output1.contains("Error"));
-    assert!(output1.chars().any(|c| c.is_numeric()));
+    assert!(output1.starts_with('₹'), "{}", output1);
 
     // Use the stored value
     let result2 = calc.evaluate_line("converted / 4");
     assert!(result2.is_some());
-    // Result should be a number (without currency symbol since variable stores plain number)
+    // Still in rupees, and can be converted again
     let output2 = result2.unwrap();
     assert!(!output2.contains("Error"));
+    assert!(output2.starts_with('₹'));
+    let output3 = calc.evaluate_line("converted to USD").unwrap();
+    assert!(output3.starts_with("$ 100"), "{}", output3);
 }
 
 #[tokio::test]
@@ -164,9 +186,12 @@ async fn test_error_cases() {
     assert!(result2.unwrap().contains("Error"));
 
     // Invalid syntax
-    let result3 = calc.evaluate_line("5 +");
+    let result3 = calc.evaluate_line("5 + +");
     assert!(result3.is_some());
     assert!(result3.unwrap().contains("error"));
+
+    // A trailing operator is still being typed, not an error
+    assert_eq!(calc.evaluate_line("100 +").unwrap(), "…");
 }
 
 #[tokio::test]
@@ -195,9 +220,8 @@ async fn test_mixed_currency_and_math() {
     // Currency annotation in arithmetic
     test_expression("50 USD + 50 USD", &["100"], &["Error"]).await;
 
-    // Large currency conversion with division
-    // Note: After division, currency context is lost
-    test_expression("(1 b INR to USD) / 4", &[], &["Error"]).await;
+    // Large currency conversion with division keeps the target currency
+    test_expression("(1 b INR to USD) / 4", &["$"], &["Error"]).await;
 
     // Direct currency conversion maintains currency
     test_expression("1 b INR to USD", &["$"], &["Error"]).await;
@@ -225,3 +249,183 @@ async fn test_sequential_calculations() {
     assert!(result.is_some());
     assert_eq!(result.unwrap(), "60");
 }
+
+#[tokio::test]
+async fn test_range_checks() {
+    let mut calc = Calculator::new().await.expect("Failed to create calculator");
+
+    calc.evaluate_line("price = 450");
+    assert_eq!(calc.evaluate_line("100 <= price <= 500").unwrap(), "1");
+    assert_eq!(calc.evaluate_line("100 <= price * 2 <= 500").unwrap(), "0");
+}
+
+#[test]
+fn test_conversion_is_case_insensitive() {
+    test_offline_expression("100 usd to inr", &["₹", "8,350"], &["Error"]);
+    test_offline_expression("100 USD To Inr.", &["₹", "8,350"], &["Error"]);
+    test_offline_expression("100 usd to eur,", &["€", "92"], &["Error"]);
+}
+
+#[test]
+fn test_currency_code_before_amount() {
+    test_offline_expression("USD 100 to INR", &["₹", "8,350"], &["Error"]);
+    test_offline_expression("100 USD to INR", &["₹", "8,350"], &["Error"]);
+    test_offline_expression("eur 50 + 50 EUR", &["€", "100"], &["Error"]);
+}
+
+#[tokio::test]
+async fn test_percent_of() {
+    test_expression("10% of 500 USD", &["$", "50"], &["Error"]).await;
+    test_expression("10% of 500", &["50"], &["$", "Error"]).await;
+    test_expression("15% of (1200 + 800)", &["300"], &["Error"]).await;
+}
+
+#[test]
+fn test_load_sheet_from_file() {
+    let path = std::env::temp_dir().join(format!("indumi_sheet_{}.txt", std::process::id()));
+    std::fs::write(&path, "rent = 1500\n\nrent * 12\n").unwrap();
+
+    let mut editor = Editor::new(Calculator::with_converter(CurrencyConverter::offline()));
+    editor.load_from_path(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(editor.lines, vec!["rent = 1500", "", "rent * 12"]);
+    let results: Vec<Option<String>> = editor
+        .lines
+        .iter()
+        .map(|line| editor.calculator.borrow_mut().evaluate_line(line))
+        .collect();
+    assert_eq!(results, vec![Some("1,500 (1.5 K)".to_string()), None, Some("18,000 (18 K)".to_string())]);
+}
+
+#[test]
+fn test_load_missing_file_keeps_empty_buffer() {
+    let mut editor = Editor::new(Calculator::with_converter(CurrencyConverter::offline()));
+    assert!(editor.load_from_path("/nonexistent/indumi/sheet.txt").is_err());
+    assert_eq!(editor.lines, vec![String::new()]);
+}
+
+#[test]
+fn test_cli_version() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_indumi"))
+        .arg("--version")
+        .output()
+        .expect("Failed to run indumi");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.trim(), format!("indumi {}", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn test_cli_batch_reads_stdin() {
+    let output = run_batch(&["--batch", "--format", "csv"], &[], "a = 2\na * 21\n");
+    assert_eq!(output, "Input,Result\na = 2,2\na * 21,42\n");
+}
+
+#[test]
+fn test_cli_eval() {
+    let run = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_indumi"))
+            .args(args)
+            .output()
+            .expect("Failed to run indumi")
+    };
+
+    let output = run(&["--eval", "2 + 3 * 4", "--no-network"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "14\n");
+
+    let output = run(&["--eval", "100 USD to INR", "--no-network", "--format", "csv"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Input,Result\n100 USD to INR,\"₹ 8,350 (8.3 K)\"\n");
+
+    let output = run(&["--eval", "2 + * 4", "--no-network"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Parse error: Cannot parse: *\n");
+}
+
+#[test]
+fn test_cli_check() {
+    let dir = std::env::temp_dir();
+    let check = |name: &str, contents: &str| {
+        let path = dir.join(format!("indumi_check_{}_{}.txt", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_indumi"))
+            .args(["--no-network", "--check"])
+            .arg(&path)
+            .output()
+            .expect("Failed to run indumi");
+        std::fs::remove_file(&path).unwrap();
+        (output, path)
+    };
+
+    let (output, _) = check("good", "rent = 1500
+
+rent * 12
+100 USD to INR
+");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+
+    let (output, path) = check("bad", "rent = 1500
+rent * food
+
+total = rent +
+");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{path}:2: Error: Undefined variable: food\n{path}:4: Error: Incomplete expression\n",
+            path = path.display()
+        )
+    );
+}
+
+fn run_batch(args: &[&str], envs: &[(&str, &str)], input: &str) -> String {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_indumi"))
+        .args(args)
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run indumi");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_cli_offline_uses_fallback_rates() {
+    let flag = run_batch(&["--batch", "--no-network"], &[], "100 USD to INR\n");
+    assert_eq!(flag, "₹ 8,350 (8.3 K)\n");
+
+    let env = run_batch(&["--batch"], &[("INDUMI_OFFLINE", "1")], "100 USD to INR\n");
+    assert_eq!(env, "₹ 8,350 (8.3 K)\n");
+}
+
+#[test]
+fn test_cli_rates_file() {
+    let path = std::env::temp_dir().join(format!("indumi_rates_{}.json", std::process::id()));
+    std::fs::write(&path, r#"{"rates": {"USD": 1, "INR": 80, "EUR": 0.5}}"#).unwrap();
+    let output = run_batch(&["--batch", "--rates-file", path.to_str().unwrap()], &[], "100 USD to INR\n50 EUR to USD\n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, "₹ 8,000 (8 K)\n$ 100\n");
+}
+
+#[test]
+fn test_cli_pasted_amount() {
+    let output = run_batch(&["--batch", "--no-network"], &[], "$1,234.56 to EUR\n");
+    assert_eq!(output, "€ 1,135.80 (1.1 K)\n");
+}
+
+#[test]
+fn test_cli_eval_negative_expression() {
+    assert_eq!(run_batch(&["--no-network", "--eval", "-5 USD to INR"], &[], ""), "₹ -417.50\n");
+    assert_eq!(run_batch(&["--no-network", "--eval", "-1/4"], &[], ""), "-0.25\n");
+}